@@ -1,29 +1,43 @@
 use rand::distributions::{Distribution, Weighted, WeightedChoice};
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
 use std::cmp;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, VecDeque};
 use std::error::Error;
-use std::fs::File;
-use std::io::{Read, Write};
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read, Write};
+use std::mem;
 use tcod::colors::{
+    self,
     Color,
     BLACK,
+    DARK_AZURE,
+    DARK_CHARTREUSE,
     DARK_RED,
+    DARK_SEA,
     DARKER_GREEN,
     DARKER_ORANGE,
     DARKER_RED,
+    DARKER_SEPIA,
     DESATURATED_GREEN,
+    FLAME,
     GOLD,
     GREEN,
+    LIGHT_AZURE,
     LIGHT_BLUE,
     LIGHT_CYAN,
     LIGHT_GREEN,
     LIGHT_GREY,
     LIGHT_RED,
+    LIGHT_SEPIA,
     LIGHT_VIOLET,
     LIGHT_YELLOW,
+    LIGHTER_GREY,
     ORANGE,
     RED,
+    SEPIA,
     SKY,
     VIOLET,
     WHITE,
@@ -46,6 +60,7 @@ use tcod::input::{
     KeyCode,
     Mouse
 };
+use tcod::line::Line;
 use tcod::map::{
     FovAlgorithm,
     Map as FovMap,
@@ -63,25 +78,115 @@ const PANEL_Y: i32 = SCREEN_HEIGHT - PANEL_HEIGHT;
 const INVENTORY_WIDTH: i32 = 50;
 const LEVEL_SCREEN_WIDTH: i32 = 40;
 const CHARACTER_SCREEN_WIDTH: i32 = 30;
+const HISTORY_SCREEN_WIDTH: i32 = 60;
+const HISTORY_RUNS_SHOWN: usize = 15;
 
 // Message log GUI constants
 const MSG_X: i32 = BAR_WIDTH + 2;
 const MSG_WIDTH: i32 = SCREEN_WIDTH - BAR_WIDTH - 2;
 const MSG_HEIGHT: usize = PANEL_HEIGHT as usize - 1;
 
-// Size of the map
-const MAP_WIDTH: i32 = 80;
-const MAP_HEIGHT: i32 = SCREEN_HEIGHT - PANEL_HEIGHT;
+// Size of the camera view (the part of the screen showing the map)
+const CAMERA_WIDTH: i32 = SCREEN_WIDTH;
+const CAMERA_HEIGHT: i32 = SCREEN_HEIGHT - PANEL_HEIGHT;
+
+// Size of the generated maps
+const MAP_WIDTH: i32 = 120;
+const MAP_HEIGHT: i32 = 80;
 
 // Room parameters for dungeon generator
 const ROOM_MAX_SIZE: i32 = 10;
 const ROOM_MIN_SIZE: i32 = 6;
-const MAX_ROOMS: i32 = 30;
-
-// Default FOV algorithm and other values
+const MAX_ROOMS: i32 = 60;
+
+// The bottom of the dungeon, where the boss guards the crown that has to be brought back to town
+const FINAL_DEPTH: u32 = 10;
+const BOSS_KIND: &str = "Fallen King";
+
+// Prefab (hand-designed room) parameters for dungeon generator
+const MAX_PREFABS: i32 = 2;
+const PREFAB_PLACEMENT_ATTEMPTS: i32 = 50;
+
+// Secret door and search parameters
+const SEARCH_RADIUS: i32 = 2;
+const SEARCH_BASE_CHANCE: i32 = 20;
+const SEARCH_LEVEL_BONUS: i32 = 10;
+const SEARCH_MAX_CHANCE: i32 = 95;
+
+// Trap parameters
+const DART_DAMAGE: i32 = 10;
+const ALARM_RADIUS: i32 = 15;
+const ALARM_STEPS: i32 = 4;
+
+// Fire and gas hazard parameters
+const FIRE_DAMAGE: i32 = 5;
+const FIRE_DURATION: i32 = 6;
+const FIRE_BARE_DURATION: i32 = 2;
+const FIRE_SPREAD_CHANCE: i32 = 40;
+const POISON_DAMAGE: i32 = 3;
+const GAS_CLOUD_STRENGTH: i32 = 8;
+const GAS_SPREAD_STRENGTH: i32 = 4;
+const GAS_CONFUSE_TURNS: i32 = 3;
+
+// Pathfinding parameters
+const MAX_PATH_NODES: usize = 2000;
+const MONSTER_PATH_COST: i32 = 5;
+
+// Default FOV algorithm and other values.
+// Line of sight is unlimited (0), but only lit tiles can actually be seen.
 const FOV_ALGO: FovAlgorithm = FovAlgorithm::Basic;
 const FOV_LIGHT_WALLS: bool = true; 
-const TORCH_RADIUS: i32 = 10;
+const FOV_RADIUS: i32 = 0;
+
+// Monster perception and stealth parameters
+const SLEEP_CHANCE: i32 = 40;
+const WANDER_CHANCE: i32 = 50;
+const NOTICE_BASE_CHANCE: i32 = 90;
+const NOTICE_DISTANCE_PENALTY: i32 = 8;
+const NOTICE_MIN_CHANCE: i32 = 5;
+const SLEEP_NOTICE_DIVISOR: i32 = 4;
+const PLAYER_STEALTH: i32 = 20;
+const LIGHT_STEALTH_PENALTY: i32 = 15;
+const SNEAK_ATTACK_MULTIPLIER: i32 = 2;
+
+// Monster AI parameters
+const SEARCH_TURNS: i32 = 10;
+const SEARCH_AREA: i32 = 3;
+const FLEE_TURNS: i32 = 10;
+
+// Energy and speed: every game turn, everyone gains energy by their speed, and can act
+// for as long as they have energy left. Actions use up energy by their cost.
+const NORMAL_SPEED: i32 = 100;
+const ACTION_COST: i32 = 100;
+const ATTACK_COST: i32 = 100;
+const USE_ITEM_COST: i32 = 150;
+const HASTE_TURNS: i32 = 20;
+const SLOW_TURNS: i32 = 10;
+
+// Allies stay within this distance of the player, and go after enemies this close to them
+const ALLY_FOLLOW_DISTANCE: i32 = 3;
+const ALLY_LEASH: i32 = 6;
+
+// Pack tactics
+const PACK_RADIUS: i32 = 5;
+const PACK_ASSEMBLED: usize = 3;
+const PACK_HOLD_DISTANCE: i32 = 5;
+
+// Noise parameters (how many steps away each noise can be heard)
+const COMBAT_NOISE: i32 = 8;
+const DOOR_NOISE: i32 = 5;
+const EXPLOSION_NOISE: i32 = 25;
+const THUNDER_NOISE: i32 = 20;
+
+// Lighting parameters
+const DARKVISION_RADIUS: i32 = 1;
+const TORCH_RADIUS: i32 = 8;
+const TORCH_FUEL: i32 = 600;
+const TORCH_LOW_FUEL: i32 = 50;
+const LANTERN_RADIUS: i32 = 10;
+const BRAZIER_RADIUS: i32 = 6;
+const BRAZIER_CHANCE: i32 = 25;
+const FIRE_LIGHT_RADIUS: i32 = 2;
 
 // Game item constants 
 const HEAL_AMOUNT: i32 = 40;
@@ -89,8 +194,17 @@ const LIGHTNING_DAMAGE: i32 = 40;
 const LIGHTNING_RANGE: i32 = 5;
 const CONFUSE_RANGE: i32 = 8;
 const CONFUSE_NUM_TURNS: i32 = 10;
+const HEAL_RANGE: i32 = 6;
+// Chance each turn that a monster caster in a fight casts a spell instead of moving or attacking
+const SPELL_CHANCE: i32 = 30;
 const FIREBALL_RADIUS: i32 = 3;
 const FIREBALL_DAMAGE: i32 = 25;
+const DIG_TURNS: i32 = 4;
+const DIG_WAND_RANGE: i32 = 12;
+
+// Town constants
+const STARTING_GOLD: i32 = 50;
+const HEALER_PRICE: i32 = 30;
 
 // Player will always be the first object
 const PLAYER: usize = 0;
@@ -99,7 +213,7 @@ const PLAYER: usize = 0;
 const LEVEL_UP_BASE: i32 = 200;
 const LEVEL_UP_FACTOR: i32 = 150;
 
-// Wall/ ground colors
+// Wall/ ground colors of the standard theme
 const COLOR_DARK_WALL: Color = Color {
     r: 0,
     g: 0,
@@ -121,6 +235,37 @@ const COLOR_LIGHT_GROUND: Color = Color {
     b: 50,
 };
 
+// Color of flammable debris on the ground
+const COLOR_DEBRIS: Color = Color {
+    r: 110,
+    g: 80,
+    b: 30,
+};
+
+// Colors of the light in lit rooms, and given off by the player's light sources
+const COLOR_AMBIENT_LIGHT: Color = Color {
+    r: 160,
+    g: 160,
+    b: 160,
+};
+const COLOR_TORCH_LIGHT: Color = Color {
+    r: 255,
+    g: 190,
+    b: 110,
+};
+const COLOR_LANTERN_LIGHT: Color = Color {
+    r: 255,
+    g: 240,
+    b: 200,
+};
+
+// Files written when dumping a map for debugging
+const MAP_DUMP_TEXT_FILE: &str = "map_dump.txt";
+const MAP_DUMP_JSON_FILE: &str = "map_dump.json";
+
+// File keeping the finished runs, across games
+const HISTORY_FILE: &str = "history";
+
 // 20 frames-per-second maximum
 const LIMIT_FPS: i32 = 20;
 
@@ -129,6 +274,7 @@ enum PlayerAction {
     TookTurn,
     DidntTakeTurn,
     Exit,
+    Won,
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -138,6 +284,58 @@ enum AI {
         previous_ai: Box<AI>,
         num_turns: i32,
     },
+    // Not (yet) aware of the player: sleeping, standing guard or roaming about
+    Wander,
+    // Head for where the player was last seen or heard
+    Hunt {
+        x: i32,
+        y: i32,
+    },
+    // Look around the spot where the trail went cold, for a number of turns
+    Search {
+        x: i32,
+        y: i32,
+        turns: i32,
+    },
+    // Run away, counting the turns spent out of the player's sight
+    Flee {
+        turns: i32,
+    },
+    // Walk back to the guard post
+    Return,
+    // Follow the player around, fighting hostile monsters along the way
+    Ally,
+}
+
+// Sounds that carry through the dungeon, drawing in the monsters that hear them
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Noise {
+    Combat,
+    Door,
+    Explosion,
+    Thunder,
+}
+
+impl Noise {
+    // Return how many steps away the noise can be heard
+    pub fn loudness(self) -> i32 {
+        match self {
+            Noise::Combat => COMBAT_NOISE,
+            Noise::Door => DOOR_NOISE,
+            Noise::Explosion => EXPLOSION_NOISE,
+            Noise::Thunder => THUNDER_NOISE,
+        }
+    }
+
+    // Return what the player hears when they can't see where the noise comes from
+    pub fn description(self) -> &'static str {
+        match self {
+            Noise::Combat => "the sounds of fighting",
+            Noise::Door => "a door creak",
+            Noise::Explosion => "a distant rumble",
+            Noise::Thunder => "a crack of thunder",
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
@@ -148,6 +346,15 @@ enum Item {
     Fireball,
     Sword,
     Shield,
+    Pickaxe,
+    DigWand,
+    Torch,
+    Lantern,
+    Haste,
+    Slow,
+    WarAxe,
+    Summon,
+    Crown,
 }
 
 // A way to track currently-used Equipment
@@ -156,6 +363,7 @@ enum Slot {
     LeftHand,
     RightHand,
     Head,
+    Belt,
 }
 
 impl std::fmt::Display for Slot {
@@ -164,14 +372,101 @@ impl std::fmt::Display for Slot {
             Slot::LeftHand => write!(f, "left hand"),
             Slot::RightHand => write!(f, "right hand"),
             Slot::Head => write!(f, "head"),
+            Slot::Belt => write!(f, "belt"),
+        }
+    }
+}
+
+// What a ranged attacker shoots
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Projectile {
+    Arrow,
+    MagicMissile,
+}
+
+impl Projectile {
+    pub fn name(self) -> &'static str {
+        match self {
+            Projectile::Arrow => "arrow",
+            Projectile::MagicMissile => "magic missile",
         }
     }
+
+    // Return the color its flight path is drawn in
+    pub fn color(self) -> Color {
+        match self {
+            Projectile::Arrow => LIGHTER_GREY,
+            Projectile::MagicMissile => LIGHT_VIOLET,
+        }
+    }
+}
+
+// Magic known by monster casters, with the same effects as the matching potions and scrolls
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Spell {
+    Heal,
+    Lightning,
+    Confuse,
+}
+
+// An attack from a distance, running out of ammo unless it needs none
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct RangedAttack {
+    projectile: Projectile,
+    range: i32,
+    power: i32,
+    // The shooter tries to stay this far away from its target
+    preferred_distance: i32,
+    ammo: Option<i32>,
+}
+
+// The flight path of a projectile, shown until the player's next turn
+#[derive(Clone, Debug)]
+struct Shot {
+    path: Vec<(i32, i32)>,
+    color: Color,
+}
+
+// How aware a monster is of the player
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Alertness {
+    Asleep,
+    Wandering,
+    Alert,
+}
+
+// The sides creatures fight on. Everyone is hostile to the player's side, and some of the
+// others are at war with each other as well.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Faction {
+    // The player and their allies
+    Player,
+    // Orcs, goblins and trolls
+    Orcs,
+    // The walking dead, and the cultists who raise them
+    Undead,
+    // Animals, that leave everyone but the player alone
+    Wildlife,
+}
+
+// Pairs of factions that fight each other on sight
+const FACTION_WARS: &[(Faction, Faction)] = &[(Faction::Orcs, Faction::Undead)];
+
+impl Faction {
+    pub fn is_hostile_to(self, other: Faction) -> bool {
+        self != other
+            && (self == Faction::Player
+                || other == Faction::Player
+                || FACTION_WARS.contains(&(self, other))
+                || FACTION_WARS.contains(&(other, self)))
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 enum DeathCallback {
     Player,
     Monster,
+    Ally,
 }
 
 impl DeathCallback {
@@ -179,11 +474,62 @@ impl DeathCallback {
         let callback = match self {
             DeathCallback::Player => player_death,
             DeathCallback::Monster => monster_death,
+            DeathCallback::Ally => ally_death,
         };
         callback(object, game);
     }
 }
 
+// Non-hostile characters, that open an interaction menu when bumped into
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+enum Npc {
+    Shopkeeper { stock: Vec<Item> },
+    Healer,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum TrapKind {
+    Dart,
+    Teleport,
+    ConfusionGas,
+    PoisonGas,
+    Alarm,
+    Trapdoor,
+}
+
+// A trap, hidden until it triggers or is found
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct Trap {
+    kind: TrapKind,
+    revealed: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum HazardKind {
+    Fire,
+    PoisonGas,
+    ConfusionGas,
+}
+
+// A persistent environmental effect (fire, gas) on a single tile,
+// that fades as its strength runs out
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct Hazard {
+    kind: HazardKind,
+    x: i32,
+    y: i32,
+    strength: i32,
+}
+
+// Light given off by an object, fading out with distance.
+// Some light sources burn out when their fuel runs out.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct Light {
+    radius: i32,
+    color: Color,
+    fuel: Option<i32>,
+}
+
 enum UseResult {
     UsedUp,
     UsedAndKept,
@@ -207,6 +553,11 @@ struct Object {
     ai: Option<AI>,
     item: Option<Item>,
     equipment: Option<Equipment>,
+    npc: Option<Npc>,
+    trap: Option<Trap>,
+    light: Option<Light>,
+    spells: Vec<Spell>,
+    path: Vec<(i32, i32)>,
 }
 
 impl Object {
@@ -225,6 +576,11 @@ impl Object {
             ai: None,
             item: None,
             equipment: None,
+            npc: None,
+            trap: None,
+            light: None,
+            spells: vec![],
+            path: vec![],
         }
     }
 
@@ -255,11 +611,12 @@ impl Object {
     }
 
     pub fn take_damage(&mut self, damage: i32, game: &mut Game) -> Option<i32> {
-        // Apply damage if possible
+        // Apply damage if possible, which also puts the fighter on its guard
         if let Some(fighter) = self.fighter.as_mut() {
             if damage > 0 {
                 fighter.hp -= damage;
             }
+            fighter.alertness = Alertness::Alert;
         }
 
         // Check for death
@@ -280,16 +637,25 @@ impl Object {
     }
 
     pub fn attack(&mut self, target: &mut Object, game: &mut Game) {
-        // Calculate attack damage
-        let damage = self.power(game) - target.defense(game);
+        // Swinging takes time
+        let cost = self.attack_cost(game);
+        spend_energy(self, cost);
+
+        // Calculate attack damage, hitting much harder when the target isn't aware of the attacker
+        let unaware = target.fighter.map_or(false, |f| f.alertness != Alertness::Alert);
+        let mut damage = self.power(game) - target.defense(game);
+        if unaware {
+            damage *= SNEAK_ATTACK_MULTIPLIER;
+        }
 
         // Check if object took damage
         if damage > 0 {
             // Make the target take some damage
+            let verb = if unaware { "sneak attacks" } else { "attacks" };
             game.messages.add(
                 format!(
-                    "{} attacks {} for {} hit points.",
-                    self.name, target.name, damage
+                    "{} {} {} for {} hit points.",
+                    self.name, verb, target.name, damage
                 ),
                 WHITE,
             );
@@ -406,6 +772,43 @@ impl Object {
         }
     }
 
+    pub fn stealth(&self, game: &Game) -> i32 {
+        // Get base stealth from Fighter component
+        let base_stealth = self.fighter.map_or(0, |f| f.stealth);
+
+        // Carrying a light gives you away
+        let is_player = self.fighter.map_or(false, |f| f.on_death == DeathCallback::Player);
+        if is_player && player_light(&game.inventory).is_some() {
+            base_stealth - LIGHT_STEALTH_PENALTY
+        } else {
+            base_stealth
+        }
+    }
+
+    pub fn speed(&self) -> i32 {
+        // Haste doubles the base speed, and slowness halves it
+        self.fighter.map_or(0, |f| {
+            let mut speed = f.speed;
+            if f.hasted_turns > 0 {
+                speed *= 2;
+            }
+            if f.slowed_turns > 0 {
+                speed /= 2;
+            }
+            speed
+        })
+    }
+
+    pub fn attack_cost(&self, game: &Game) -> i32 {
+        // Heavy weapons take longer to swing
+        let delay: i32 = self
+            .get_all_equipped(game)
+            .iter()
+            .map(|e| e.attack_delay)
+            .sum();
+        ATTACK_COST + delay
+    }
+
     pub fn power(&self, game: &Game) -> i32 {
         // Get base power from Fighter component
         let base_power = self.fighter.map_or(0, |f| f.base_power);
@@ -421,10 +824,10 @@ impl Object {
         base_power + bonus
     }
 
-    // Set the color and then draw the character that represents this object at its position
-    pub fn draw(&self, con: &mut dyn Console) {
+    // Set the color and then draw the character that represents this object at the given console position
+    pub fn draw(&self, con: &mut dyn Console, x: i32, y: i32) {
         con.set_default_foreground(self.color);
-        con.put_char(self.x, self.y, self.char, BackgroundFlag::None);
+        con.put_char(x, y, self.char, BackgroundFlag::None);
     }
 }
 
@@ -437,6 +840,35 @@ struct Fighter {
     base_power: i32,
     xp: i32,
     on_death: DeathCallback,
+    confused_turns: i32,
+    // Run away when HP drops below this percentage of the maximum
+    flee_below: i32,
+    // Hang back at this distance from a healthy player, instead of closing in
+    keep_distance: i32,
+    // How far away the player can be noticed, and how aware of the player it is
+    sight_radius: i32,
+    alertness: Alertness,
+    // Makes it harder to be noticed
+    stealth: i32,
+    // Where it stands guard, returning there once it loses track of the player
+    guard_post: Option<(i32, i32)>,
+    // Where the player was last seen or heard, to go looking for them there
+    last_seen: Option<(i32, i32)>,
+    ranged: Option<RangedAttack>,
+    // The pack it hunts with, if any
+    pack: Option<Pack>,
+    speed: i32,
+    energy: i32,
+    hasted_turns: i32,
+    slowed_turns: i32,
+    faction: Faction,
+}
+
+// Membership of a group of monsters that hunt together under a leader
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct Pack {
+    id: u32,
+    leader: bool,
 }
 
 // An object that can be equipped, yielding bonuses.
@@ -447,6 +879,8 @@ struct Equipment {
     max_hp_bonus: i32,
     defense_bonus: i32,
     power_bonus: i32,
+    // Extra energy each swing costs
+    attack_delay: i32,
 }
 
 // Console messages
@@ -504,14 +938,645 @@ impl Rect {
             && (self.y1 <= other.y2)
             && (self.y2 >= other.y1)
     }
+
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        // Returns true if the position lies within this rectangle, edges included
+        x >= self.x1 && x <= self.x2 && y >= self.y1 && y <= self.y2
+    }
+}
+
+// Progress of the player digging through a wall tile
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct Digging {
+    x: i32,
+    y: i32,
+    turns: i32,
+}
+
+// A hand-designed room template, stamped into the map during generation.
+// Legend:
+//   '#' wall, '.' floor, '+' door (connected to the rest of the level),
+//   'o' orc, 'T' troll, '!' healing potion, '/' sword, '[' shield,
+//   '?' random item for the current level, '*' brazier
+struct Prefab {
+    min_level: u32,
+    max_level: u32,
+    rarity: u32,
+    layout: &'static [&'static str],
+}
+
+impl Prefab {
+    pub fn width(&self) -> i32 {
+        self.layout[0].len() as i32
+    }
+
+    pub fn height(&self) -> i32 {
+        self.layout.len() as i32
+    }
+}
+
+// The level a prefab is stamped into, which decides what its monsters and random items are
+#[derive(Clone, Copy)]
+struct PrefabContext {
+    level: u32,
+    theme: Theme,
 }
 
+// Library of prefabs that can appear in generated levels
+const PREFABS: &[Prefab] = &[
+    // Shrine
+    Prefab {
+        min_level: 1,
+        max_level: 4,
+        rarity: 30,
+        layout: &[
+            "###+###",
+            "#.....#",
+            "#.#!#.#",
+            "#.....#",
+            "#*#.#*#",
+            "#.....#",
+            "#######",
+        ],
+    },
+    // Treasure vault
+    Prefab {
+        min_level: 2,
+        max_level: u32::MAX,
+        rarity: 15,
+        layout: &[
+            "#########",
+            "#?.....?#",
+            "#.#####.#",
+            "#.#?!?#.#",
+            "#.##.##.#",
+            "#...o...#",
+            "####+####",
+        ],
+    },
+    // Orc lair
+    Prefab {
+        min_level: 2,
+        max_level: 6,
+        rarity: 20,
+        layout: &[
+            "###########",
+            "#o...#...o#",
+            "#..o...o..#",
+            "+....?....+",
+            "#..o...o..#",
+            "#!...#...!#",
+            "###########",
+        ],
+    },
+    // Troll den
+    Prefab {
+        min_level: 5,
+        max_level: u32::MAX,
+        rarity: 20,
+        layout: &[
+            "#####+#####",
+            "#T.......T#",
+            "#..#...#..#",
+            "#....?....#",
+            "#..#...#..#",
+            "#/...T...[#",
+            "###########",
+        ],
+    },
+];
+
+// The surface town, the hub the player returns to between dungeon trips.
+// Legend:
+//   '#' wall, '.' floor, '@' player start, '<' dungeon entrance,
+//   'A' alchemist, 'W' armourer, 'H' healer
+const TOWN_LAYOUT: &[&str] = &[
+    "##################################################",
+    "#................................................#",
+    "#...#######......................#######.........#",
+    "#...#.....#......................#.....#.........#",
+    "#...#..A..#......................#..W..#.........#",
+    "#...#.....#......................#.....#.........#",
+    "#...###.###......................###.###.........#",
+    "#................................................#",
+    "#................................................#",
+    "#......................@.........................#",
+    "#................................................#",
+    "#................................................#",
+    "#...#######..............................#####...#",
+    "#...#.....#..............................#.<.#...#",
+    "#...#..H..#..............................#...#...#",
+    "#...#.....#..............................##.##...#",
+    "#...###.###......................................#",
+    "#................................................#",
+    "##################################################",
+];
+
+// Kinds of monsters that can appear in the dungeon
+const MONSTERS: &[MonsterTemplate] = &[
+    MonsterTemplate {
+        name: "orc",
+        char: 'o',
+        color: DESATURATED_GREEN,
+        hp: 20,
+        defense: 0,
+        power: 4,
+        xp: 35,
+        flee_below: 0,
+        keep_distance: 0,
+        sight_radius: 8,
+        speed: NORMAL_SPEED,
+        light: None,
+        ranged: None,
+        spells: &[],
+        faction: Faction::Orcs,
+    },
+    MonsterTemplate {
+        name: "troll",
+        char: 'T',
+        color: DARKER_GREEN,
+        hp: 30,
+        defense: 2,
+        power: 8,
+        xp: 100,
+        flee_below: 0,
+        keep_distance: 0,
+        sight_radius: 6,
+        speed: NORMAL_SPEED,
+        light: None,
+        ranged: None,
+        spells: &[],
+        faction: Faction::Orcs,
+    },
+    MonsterTemplate {
+        name: "skeleton",
+        char: 's',
+        color: LIGHTER_GREY,
+        hp: 16,
+        defense: 1,
+        power: 5,
+        xp: 40,
+        flee_below: 0,
+        keep_distance: 0,
+        sight_radius: 10,
+        speed: NORMAL_SPEED,
+        light: None,
+        ranged: None,
+        spells: &[],
+        faction: Faction::Undead,
+    },
+    MonsterTemplate {
+        name: "zombie",
+        char: 'z',
+        color: DARK_SEA,
+        hp: 28,
+        defense: 0,
+        power: 5,
+        xp: 50,
+        flee_below: 0,
+        keep_distance: 0,
+        sight_radius: 5,
+        speed: 50,
+        light: None,
+        ranged: None,
+        spells: &[],
+        faction: Faction::Undead,
+    },
+    MonsterTemplate {
+        name: "giant rat",
+        char: 'r',
+        color: SEPIA,
+        hp: 8,
+        defense: 0,
+        power: 3,
+        xp: 10,
+        flee_below: 50,
+        keep_distance: 0,
+        sight_radius: 6,
+        speed: NORMAL_SPEED,
+        light: None,
+        ranged: None,
+        spells: &[],
+        faction: Faction::Wildlife,
+    },
+    MonsterTemplate {
+        name: "cave eel",
+        char: 'e',
+        color: DARK_AZURE,
+        hp: 14,
+        defense: 0,
+        power: 6,
+        xp: 30,
+        flee_below: 0,
+        keep_distance: 0,
+        sight_radius: 5,
+        speed: NORMAL_SPEED,
+        light: Some(Light {
+            radius: 2,
+            color: SKY,
+            fuel: None,
+        }),
+        ranged: None,
+        spells: &[],
+        faction: Faction::Wildlife,
+    },
+    MonsterTemplate {
+        name: "goblin",
+        char: 'g',
+        color: DARK_CHARTREUSE,
+        hp: 12,
+        defense: 0,
+        power: 3,
+        xp: 20,
+        flee_below: 30,
+        keep_distance: 3,
+        sight_radius: 9,
+        speed: NORMAL_SPEED,
+        light: None,
+        ranged: None,
+        spells: &[],
+        faction: Faction::Orcs,
+    },
+    MonsterTemplate {
+        name: "goblin archer",
+        char: 'g',
+        color: LIGHT_GREEN,
+        hp: 10,
+        defense: 0,
+        power: 2,
+        xp: 30,
+        flee_below: 30,
+        keep_distance: 0,
+        sight_radius: 10,
+        speed: NORMAL_SPEED,
+        light: None,
+        ranged: Some(RangedAttack {
+            projectile: Projectile::Arrow,
+            range: 8,
+            power: 4,
+            preferred_distance: 4,
+            ammo: Some(8),
+        }),
+        spells: &[],
+        faction: Faction::Orcs,
+    },
+    MonsterTemplate {
+        name: "cultist",
+        char: 'c',
+        color: DARK_RED,
+        hp: 14,
+        defense: 0,
+        power: 2,
+        xp: 45,
+        flee_below: 0,
+        keep_distance: 0,
+        sight_radius: 8,
+        speed: NORMAL_SPEED,
+        light: None,
+        ranged: Some(RangedAttack {
+            projectile: Projectile::MagicMissile,
+            range: 6,
+            power: 5,
+            preferred_distance: 3,
+            ammo: None,
+        }),
+        spells: &[],
+        faction: Faction::Undead,
+    },
+    MonsterTemplate {
+        name: "orc shaman",
+        char: 'o',
+        color: LIGHT_VIOLET,
+        hp: 12,
+        defense: 0,
+        power: 2,
+        xp: 60,
+        flee_below: 25,
+        keep_distance: 3,
+        sight_radius: 8,
+        speed: NORMAL_SPEED,
+        light: None,
+        ranged: None,
+        spells: &[Spell::Heal, Spell::Confuse, Spell::Lightning],
+        faction: Faction::Orcs,
+    },
+    MonsterTemplate {
+        name: "orc captain",
+        char: 'o',
+        color: DARKER_GREEN,
+        hp: 28,
+        defense: 1,
+        power: 5,
+        xp: 80,
+        flee_below: 0,
+        keep_distance: 0,
+        sight_radius: 9,
+        speed: NORMAL_SPEED,
+        light: None,
+        ranged: None,
+        spells: &[],
+        faction: Faction::Orcs,
+    },
+    MonsterTemplate {
+        name: "Fallen King",
+        char: 'K',
+        color: GOLD,
+        hp: 160,
+        defense: 4,
+        power: 12,
+        xp: 1000,
+        flee_below: 0,
+        keep_distance: 0,
+        sight_radius: 10,
+        speed: NORMAL_SPEED,
+        light: Some(Light {
+            radius: 3,
+            color: LIGHT_VIOLET,
+            fuel: None,
+        }),
+        ranged: None,
+        spells: &[Spell::Confuse, Spell::Lightning],
+        faction: Faction::Undead,
+    },
+    MonsterTemplate {
+        name: "dog",
+        char: 'd',
+        color: LIGHT_SEPIA,
+        hp: 20,
+        defense: 0,
+        power: 3,
+        xp: 0,
+        flee_below: 0,
+        keep_distance: 0,
+        sight_radius: 8,
+        speed: NORMAL_SPEED,
+        light: None,
+        ranged: None,
+        spells: &[],
+        faction: Faction::Player,
+    },
+    MonsterTemplate {
+        name: "spirit wolf",
+        char: 'w',
+        color: LIGHT_AZURE,
+        hp: 25,
+        defense: 1,
+        power: 5,
+        xp: 0,
+        flee_below: 0,
+        keep_distance: 0,
+        sight_radius: 8,
+        speed: NORMAL_SPEED,
+        light: Some(Light {
+            radius: 2,
+            color: LIGHT_AZURE,
+            fuel: None,
+        }),
+        ranged: None,
+        spells: &[],
+        faction: Faction::Player,
+    },
+    MonsterTemplate {
+        name: "bat",
+        char: 'b',
+        color: DARKER_SEPIA,
+        hp: 4,
+        defense: 0,
+        power: 2,
+        xp: 15,
+        flee_below: 0,
+        keep_distance: 0,
+        sight_radius: 6,
+        speed: 200,
+        light: None,
+        ranged: None,
+        spells: &[],
+        faction: Faction::Wildlife,
+    },
+];
+
+// Leaders that bring a pack of followers along
+const PACKS: &[PackData] = &[PackData {
+    leader: "orc captain",
+    follower: "orc",
+    min_size: 2,
+    max_size: 4,
+    follower_flee_below: 25,
+}];
+
+// Extra hit points, power and xp of monsters found deeper down, in percent
+const MONSTER_SCALING: &[Transition] = &[
+    Transition { level: 3, value: 10 },
+    Transition { level: 5, value: 25 },
+    Transition { level: 7, value: 40 },
+    Transition { level: 9, value: 60 },
+];
+
+// Chance of a monster being an elite variant, in percent
+const ELITE_CHANCE: &[Transition] = &[
+    Transition { level: 2, value: 5 },
+    Transition { level: 4, value: 10 },
+    Transition { level: 7, value: 15 },
+];
+
+// Elite variants any monster can come in, weighted by depth
+const ELITES: &[EliteData] = &[
+    EliteData {
+        prefix: "Vicious",
+        color: LIGHT_RED,
+        chance: &[Transition { level: 2, value: 40 }],
+        hp_bonus: 0,
+        power_bonus: 3,
+        defense_bonus: 0,
+        xp_bonus: 50,
+        speed_bonus: 0,
+        spells: &[],
+    },
+    EliteData {
+        prefix: "Hulking",
+        color: DARKER_ORANGE,
+        chance: &[Transition { level: 2, value: 30 }],
+        hp_bonus: 100,
+        power_bonus: 1,
+        defense_bonus: 1,
+        xp_bonus: 60,
+        speed_bonus: 0,
+        spells: &[],
+    },
+    EliteData {
+        prefix: "Swift",
+        color: LIGHT_CYAN,
+        chance: &[Transition { level: 4, value: 20 }],
+        hp_bonus: 0,
+        power_bonus: 1,
+        defense_bonus: 0,
+        xp_bonus: 60,
+        speed_bonus: 50,
+        spells: &[],
+    },
+    EliteData {
+        prefix: "Hexing",
+        color: LIGHT_VIOLET,
+        chance: &[Transition { level: 5, value: 15 }],
+        hp_bonus: 0,
+        power_bonus: 0,
+        defense_bonus: 0,
+        xp_bonus: 80,
+        speed_bonus: 0,
+        spells: &[Spell::Confuse],
+    },
+    EliteData {
+        prefix: "Champion",
+        color: YELLOW,
+        chance: &[Transition { level: 7, value: 10 }],
+        hp_bonus: 50,
+        power_bonus: 2,
+        defense_bonus: 2,
+        xp_bonus: 100,
+        speed_bonus: 0,
+        spells: &[Spell::Heal],
+    },
+];
+
+// Level themes, weighted by depth
+const THEMES: &[ThemeData] = &[
+    ThemeData {
+        theme: Theme::Standard,
+        chance: &[
+            Transition { level: 1, value: 100 },
+            Transition { level: 4, value: 60 },
+        ],
+        room_min_size: ROOM_MIN_SIZE,
+        room_max_size: ROOM_MAX_SIZE,
+        max_rooms: MAX_ROOMS,
+        debris_chance: 5,
+        dark_wall: COLOR_DARK_WALL,
+        light_wall: COLOR_LIGHT_WALL,
+        dark_ground: COLOR_DARK_GROUND,
+        light_ground: COLOR_LIGHT_GROUND,
+        monsters: &[
+            ("orc", &[Transition { level: 1, value: 80 }]),
+            ("goblin archer", &[Transition { level: 2, value: 15 }]),
+            ("cultist", &[Transition { level: 4, value: 10 }]),
+            ("orc shaman", &[Transition { level: 3, value: 10 }]),
+            ("orc captain", &[Transition { level: 2, value: 8 }]),
+            (
+                "troll",
+                &[
+                    Transition { level: 3, value: 15 },
+                    Transition { level: 5, value: 30 },
+                    Transition { level: 7, value: 60 },
+                ],
+            ),
+        ],
+        item_weights: &[],
+        feeling: "The air is stale, and the halls are silent.",
+    },
+    ThemeData {
+        theme: Theme::Crypt,
+        chance: &[
+            Transition { level: 3, value: 25 },
+            Transition { level: 6, value: 40 },
+        ],
+        room_min_size: 4,
+        room_max_size: 7,
+        max_rooms: 80,
+        debris_chance: 10,
+        dark_wall: Color { r: 30, g: 30, b: 40 },
+        light_wall: Color { r: 110, g: 110, b: 120 },
+        dark_ground: Color { r: 45, g: 45, b: 60 },
+        light_ground: Color { r: 150, g: 150, b: 140 },
+        monsters: &[
+            ("skeleton", &[Transition { level: 1, value: 60 }]),
+            (
+                "zombie",
+                &[
+                    Transition { level: 1, value: 30 },
+                    Transition { level: 5, value: 50 },
+                ],
+            ),
+            ("troll", &[Transition { level: 6, value: 15 }]),
+            ("cultist", &[Transition { level: 2, value: 25 }]),
+        ],
+        item_weights: &[(Item::Heal, 50), (Item::Confuse, 150)],
+        feeling: "You smell the dust of old bones. This is a crypt.",
+    },
+    ThemeData {
+        theme: Theme::FloodedMine,
+        chance: &[
+            Transition { level: 2, value: 20 },
+            Transition { level: 5, value: 30 },
+        ],
+        room_min_size: 8,
+        room_max_size: 14,
+        max_rooms: 35,
+        debris_chance: 0,
+        dark_wall: Color { r: 10, g: 30, b: 60 },
+        light_wall: Color { r: 70, g: 90, b: 110 },
+        dark_ground: Color { r: 20, g: 50, b: 90 },
+        light_ground: Color { r: 60, g: 130, b: 170 },
+        monsters: &[
+            ("giant rat", &[Transition { level: 1, value: 50 }]),
+            ("bat", &[Transition { level: 1, value: 25 }]),
+            (
+                "cave eel",
+                &[
+                    Transition { level: 1, value: 30 },
+                    Transition { level: 4, value: 50 },
+                ],
+            ),
+            (
+                "troll",
+                &[
+                    Transition { level: 4, value: 20 },
+                    Transition { level: 7, value: 40 },
+                ],
+            ),
+        ],
+        item_weights: &[(Item::Lightning, 150), (Item::Fireball, 50)],
+        feeling: "Water drips from the ceiling of an abandoned, flooded mine.",
+    },
+    ThemeData {
+        theme: Theme::GoblinWarren,
+        chance: &[
+            Transition { level: 1, value: 15 },
+            Transition { level: 4, value: 30 },
+        ],
+        room_min_size: 4,
+        room_max_size: 6,
+        max_rooms: 100,
+        debris_chance: 25,
+        dark_wall: Color { r: 50, g: 30, b: 10 },
+        light_wall: Color { r: 120, g: 80, b: 40 },
+        dark_ground: Color { r: 60, g: 45, b: 20 },
+        light_ground: Color { r: 160, g: 120, b: 60 },
+        monsters: &[
+            ("goblin", &[Transition { level: 1, value: 80 }]),
+            ("goblin archer", &[Transition { level: 1, value: 30 }]),
+            (
+                "orc",
+                &[
+                    Transition { level: 1, value: 20 },
+                    Transition { level: 4, value: 40 },
+                ],
+            ),
+            ("troll", &[Transition { level: 5, value: 20 }]),
+        ],
+        item_weights: &[(Item::Sword, 200), (Item::Shield, 200)],
+        feeling: "You hear chattering and scurrying all around. Goblins!",
+    },
+];
+
 // A tile of the map and its properties
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 struct Tile {
     blocked: bool,
     explored: bool,
     block_sight: bool,
+    door: bool,
+    hidden: bool,
+    flammable: bool,
+    lit: bool,
 }
 
 impl Tile {
@@ -520,6 +1585,10 @@ impl Tile {
             blocked: false,
             explored: false,
             block_sight: false,
+            door: false,
+            hidden: false,
+            flammable: false,
+            lit: false,
         }
     }
 
@@ -528,6 +1597,44 @@ impl Tile {
             blocked: true,
             explored: false,
             block_sight: true,
+            door: false,
+            hidden: false,
+            flammable: false,
+            lit: false,
+        }
+    }
+
+    // An open doorway
+    pub fn door() -> Self {
+        Tile {
+            blocked: false,
+            explored: false,
+            block_sight: false,
+            door: true,
+            hidden: false,
+            flammable: false,
+            lit: false,
+        }
+    }
+
+    // A door that looks (and behaves) like a wall until it is found
+    pub fn secret_door() -> Self {
+        Tile {
+            blocked: true,
+            explored: false,
+            block_sight: true,
+            door: true,
+            hidden: true,
+            flammable: false,
+            lit: false,
+        }
+    }
+
+    // Ground covered in straw, cobwebs or other debris, that fire spreads over
+    pub fn debris() -> Self {
+        Tile {
+            flammable: true,
+            ..Tile::empty()
         }
     }
 }
@@ -535,6 +1642,21 @@ impl Tile {
 // Map type (2D array of Tiles)
 type Map = Vec<Vec<Tile>>;
 
+// Return the (width, height) of a map
+fn map_size(map: &Map) -> (i32, i32) {
+    (map.len() as i32, map.first().map_or(0, |column| column.len()) as i32)
+}
+
+// A visited level that the player has left, kept exactly as it was left
+#[derive(Serialize, Deserialize)]
+struct Level {
+    map: Map,
+    objects: Vec<Object>,
+    seed: u64,
+    theme: Theme,
+    hazards: Vec<Hazard>,
+}
+
 // Game struct
 #[derive(Serialize, Deserialize)]
 struct Game {
@@ -542,6 +1664,52 @@ struct Game {
     messages: Messages,
     inventory: Vec<Object>,
     dungeon_level: u32,
+    levels: BTreeMap<u32, Level>,
+    gold: i32,
+    seed: u64,
+    theme: Theme,
+    hazards: Vec<Hazard>,
+    digging: Option<Digging>,
+    // Bumped whenever the terrain changes, so cached distance maps know to recompute
+    #[serde(skip)]
+    map_revision: u32,
+    #[serde(skip)]
+    dijkstra: DijkstraCache,
+    #[serde(skip)]
+    auto_move: Option<AutoMove>,
+    #[serde(skip)]
+    shots: Vec<Shot>,
+}
+
+// A distance field over the map, counting the steps from the nearest of one or more sources
+#[derive(Clone, Debug, Default, PartialEq)]
+struct DijkstraMap {
+    width: i32,
+    distances: Vec<i32>,
+}
+
+// Distance maps kept between turns, along with the inputs they were computed from
+#[derive(Debug, Default)]
+struct DijkstraCache {
+    // (dungeon level, map revision, player position)
+    player_key: Option<(u32, u32, (i32, i32))>,
+    // Leads towards the player
+    approach: DijkstraMap,
+    // Leads away from the player, preferring open ground over dead ends
+    flee: DijkstraMap,
+    // (dungeon level, map revision, explored tiles, revealed traps)
+    explore_key: Option<(u32, u32, usize, usize)>,
+    // Leads towards the nearest unexplored tile
+    explore: DijkstraMap,
+}
+
+// Something the player keeps doing on their own until interrupted
+#[derive(Clone, Debug, PartialEq)]
+enum AutoMove {
+    // Walk towards unexplored tiles, remembering which items have already been seen
+    Explore { seen_items: Vec<(i32, i32)> },
+    // Walk to a chosen tile, following a distance map leading there
+    Travel { destination: (i32, i32), field: DijkstraMap },
 }
 
 struct Transition {
@@ -549,14 +1717,123 @@ struct Transition {
     value: u32,
 }
 
+// Level themes, changing how a dungeon level is generated, drawn and populated
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Theme {
+    Standard,
+    Crypt,
+    FloodedMine,
+    GoblinWarren,
+}
+
+impl Theme {
+    // Return the data describing this theme
+    pub fn data(self) -> &'static ThemeData {
+        THEMES
+            .iter()
+            .find(|data| data.theme == self)
+            .expect("Missing theme data")
+    }
+}
+
+// Data describing a level theme
+struct ThemeData {
+    theme: Theme,
+    // Chance of the theme being picked, based on level
+    chance: &'static [Transition],
+    // Generator parameters
+    room_min_size: i32,
+    room_max_size: i32,
+    max_rooms: i32,
+    // Chance (percentage) of each room tile being covered in flammable debris
+    debris_chance: i32,
+    // Wall/ ground palette
+    dark_wall: Color,
+    light_wall: Color,
+    dark_ground: Color,
+    light_ground: Color,
+    // Monster kinds, with their chances based on level
+    monsters: &'static [(&'static str, &'static [Transition])],
+    // Item weights, as percentages of the base item chances
+    item_weights: &'static [(Item, u32)],
+    // Message shown when the player arrives on the level
+    feeling: &'static str,
+}
+
+// A leader and the kind of followers placed around it, in a pack of
+// min_size to max_size followers
+struct PackData {
+    leader: &'static str,
+    follower: &'static str,
+    min_size: i32,
+    max_size: i32,
+    // Followers lose heart and flee below this percentage of their hit points
+    follower_flee_below: i32,
+}
+
+// A stronger variant of a monster, named with a prefix. Hit point, xp and speed bonuses are
+// in percent, and some variants gain spells as a special ability.
+struct EliteData {
+    prefix: &'static str,
+    color: Color,
+    chance: &'static [Transition],
+    hp_bonus: i32,
+    power_bonus: i32,
+    defense_bonus: i32,
+    xp_bonus: i32,
+    speed_bonus: i32,
+    spells: &'static [Spell],
+}
+
+// Stats of a kind of monster
+struct MonsterTemplate {
+    name: &'static str,
+    char: char,
+    color: Color,
+    hp: i32,
+    defense: i32,
+    power: i32,
+    xp: i32,
+    flee_below: i32,
+    keep_distance: i32,
+    sight_radius: i32,
+    speed: i32,
+    light: Option<Light>,
+    ranged: Option<RangedAttack>,
+    spells: &'static [Spell],
+    faction: Faction,
+}
+
 // Tcod struct
 struct Tcod {
     root: Root,
     con: Offscreen,
     panel: Offscreen,
     fov: FovMap,
+    light_fov: FovMap,
+    lights: LightMap,
     key: Key,  
     mouse: Mouse,
+    camera: (i32, i32),
+}
+
+// The light falling on each tile of the map, and which of the tiles the player can see
+#[derive(Default)]
+struct LightMap {
+    width: i32,
+    light: Vec<Color>,
+    visible: Vec<bool>,
+}
+
+impl LightMap {
+    // Return the color and intensity of the light falling on a tile
+    pub fn light(&self, x: i32, y: i32) -> Color {
+        self.light.get((x + y * self.width) as usize).cloned().unwrap_or(BLACK)
+    }
+
+    pub fn is_visible(&self, x: i32, y: i32) -> bool {
+        x >= 0 && x < self.width && self.visible.get((x + y * self.width) as usize).cloned().unwrap_or(false)
+    }
 }
 
 // Mutably borrow two *separate* elements from the given slice.
@@ -581,6 +1858,17 @@ fn create_room(room: Rect, map: &mut Map) {
     }
 }
 
+fn scatter_debris(room: Rect, map: &mut Map, chance: i32, rng: &mut StdRng) {
+    // Go through the tiles in the rectangle and randomly cover them in debris
+    for x in (room.x1 + 1)..room.x2 {
+        for y in (room.y1 + 1)..room.y2 {
+            if rng.gen_range(0, 100) < chance {
+                map[x as usize][y as usize] = Tile::debris();
+            }
+        }
+    }
+}
+
 fn create_h_tunnel(x1: i32, x2: i32, y: i32, map: &mut Map) {
     // Horizontal tunnel
     // `min()` and `max()` are used in case `x1 > x2`
@@ -597,494 +1885,2837 @@ fn create_v_tunnel(y1: i32, y2: i32, x: i32, map: &mut Map) {
     }
 }
 
-fn place_objects(room: Rect, map: &Map, objects: &mut Vec<Object>, level: u32) {
-    // Define maximum number of monsters per room based on level
-    let max_monsters = from_dungeon_level(
-        &[
-            Transition { level: 1, value: 2 },
-            Transition { level: 4, value: 3 },
-            Transition { level: 6, value: 5 },
-        ],
-        level,
-    );
-    // Choose random number of monsters
-    let num_monsters = rand::thread_rng().gen_range(0, max_monsters + 1);
+fn create_monster(kind: &str, x: i32, y: i32) -> Object {
+    // Look up the stats of this kind of monster
+    let template = MONSTERS
+        .iter()
+        .find(|template| template.name == kind)
+        .unwrap_or_else(|| panic!("Unknown monster kind: {}", kind));
+
+    // Create the monster
+    let mut monster = Object::new(x, y, template.char, template.name, template.color, true);
+
+    // Set monster components
+    monster.fighter = Some(Fighter {
+        hp: template.hp,
+        base_max_hp: template.hp,
+        base_defense: template.defense,
+        base_power: template.power,
+        xp: template.xp,
+        on_death: DeathCallback::Monster,
+        confused_turns: 0,
+        flee_below: template.flee_below,
+        keep_distance: template.keep_distance,
+        sight_radius: template.sight_radius,
+        alertness: Alertness::Wandering,
+        stealth: 0,
+        guard_post: None,
+        last_seen: None,
+        ranged: template.ranged,
+        pack: None,
+        speed: template.speed,
+        energy: 0,
+        hasted_turns: 0,
+        slowed_turns: 0,
+        faction: template.faction,
+    });
+    monster.ai = Some(AI::Wander);
+    monster.light = template.light;
+    monster.spells = template.spells.to_vec();
 
-    for _ in 0..num_monsters {
-        // Choose random spot for this monster
-        let x = rand::thread_rng().gen_range(room.x1 + 1, room.x2);
-        let y = rand::thread_rng().gen_range(room.y1 + 1, room.y2);
+    // Give the monster life!
+    monster.alive = true;
+
+    // Return the monster
+    monster
+}
+
+// Create a creature that fights on the player's side
+fn create_ally(kind: &str, x: i32, y: i32) -> Object {
+    let mut ally = create_monster(kind, x, y);
+    if let Some(fighter) = ally.fighter.as_mut() {
+        fighter.on_death = DeathCallback::Ally;
+        fighter.alertness = Alertness::Alert;
+        fighter.faction = Faction::Player;
+        fighter.xp = 0;
+    }
+    ally.ai = Some(AI::Ally);
+    ally
+}
+
+/// Create a monster found at the given depth. The deeper it is, the tougher it gets, and the
+/// more likely it is to be an elite variant.
+fn create_dungeon_monster(kind: &str, x: i32, y: i32, level: u32, rng: &mut StdRng) -> Object {
+    let mut monster = create_monster(kind, x, y);
+
+    // Scale the stats with depth
+    let scaling = from_dungeon_level(MONSTER_SCALING, level) as i32;
+    if let Some(fighter) = monster.fighter.as_mut() {
+        fighter.base_max_hp += fighter.base_max_hp * scaling / 100;
+        fighter.hp = fighter.base_max_hp;
+        fighter.base_power += fighter.base_power * scaling / 100;
+        fighter.xp += fighter.xp * scaling / 100;
+    }
+
+    // Maybe make it an elite
+    if rng.gen_range(0, 100) < from_dungeon_level(ELITE_CHANCE, level) as i32 {
+        make_elite(&mut monster, random_elite(level, rng));
+    }
+    monster
+}
+
+// Choose a random elite variant, based on level
+fn random_elite(level: u32, rng: &mut StdRng) -> &'static EliteData {
+    let mut elite_chances: Vec<_> = ELITES
+        .iter()
+        .map(|elite| Weighted {
+            weight: from_dungeon_level(elite.chance, level),
+            item: elite,
+        })
+        .collect();
+    WeightedChoice::new(&mut elite_chances).sample(rng)
+}
+
+// Turn a monster into an elite variant, boosting its stats and maybe granting it spells
+fn make_elite(monster: &mut Object, elite: &EliteData) {
+    monster.name = format!("{} {}", elite.prefix, monster.name);
+    monster.color = elite.color;
+    if let Some(fighter) = monster.fighter.as_mut() {
+        fighter.base_max_hp += fighter.base_max_hp * elite.hp_bonus / 100;
+        fighter.hp = fighter.base_max_hp;
+        fighter.base_power += elite.power_bonus;
+        fighter.base_defense += elite.defense_bonus;
+        fighter.xp += fighter.xp * elite.xp_bonus / 100;
+        fighter.speed += fighter.speed * elite.speed_bonus / 100;
+    }
+    monster.spells.extend_from_slice(elite.spells);
+}
+
+// Some monsters are found fast asleep
+fn roll_sleep(monster: &mut Object, rng: &mut StdRng) {
+    if rng.gen_range(0, 100) < SLEEP_CHANCE {
+        if let Some(fighter) = monster.fighter.as_mut() {
+            fighter.alertness = Alertness::Asleep;
+        }
+    }
+}
+
+fn create_item(item: Item, x: i32, y: i32) -> Object {
+    let mut object = match item {
+        Item::Heal => {
+            // Create a healing potion
+            let mut object = Object::new(x, y, '!', "healing potion", VIOLET, false);
+            object.item = Some(Item::Heal);
+
+            // Return the item
+            object
+        }
+        Item::Lightning => {
+            // Create a lightning bolt scroll
+            let mut object = Object::new(x, y, '#', "scroll of lightning bolt", LIGHT_YELLOW, false);
+            object.item = Some(Item::Lightning);
+
+            // Return the item
+            object
+        }
+        Item::Confuse => {
+            // Create a confuse scroll
+            let mut object = Object::new(x, y, '#', "scroll of confusion", LIGHT_YELLOW, false);
+            object.item = Some(Item::Confuse);
+            
+            // Return the object
+            object
+        } 
+        Item::Fireball => {
+            // Create a fireball scroll
+            let mut object = Object::new(x, y, '#', "scroll of fireball", LIGHT_YELLOW, false);
+            object.item = Some(Item::Fireball);
+            
+            // Return the object
+            object
+        }
+        Item::Sword => {
+            // Create a sword
+            let mut object = Object::new(x, y, '/', "sword", SKY, false);
+            object.item = Some(Item::Sword);
+            object.equipment = Some(Equipment {
+                equipped: false,
+                slot: Slot::RightHand,
+                max_hp_bonus: 0,
+                defense_bonus: 0,
+                power_bonus: 3,
+                attack_delay: 0,
+            });
+
+            // Return the object
+            object
+        }
+
+        Item::Shield => {
+            // Create a shield
+            let mut object = Object::new(x, y, '[', "shield", DARKER_ORANGE, false);
+            object.item = Some(Item::Shield);
+            object.equipment = Some(Equipment {
+                equipped: false,
+                slot: Slot::LeftHand,
+                max_hp_bonus: 0,
+                defense_bonus: 1,
+                power_bonus: 0,
+                attack_delay: 0,
+            });
+
+            // Return the object
+            object
+        }
+        Item::Pickaxe => {
+            // Create a pickaxe, that digs through walls when wielded
+            let mut object = Object::new(x, y, '(', "pickaxe", LIGHT_GREY, false);
+            object.item = Some(Item::Pickaxe);
+            object.equipment = Some(Equipment {
+                equipped: false,
+                slot: Slot::RightHand,
+                max_hp_bonus: 0,
+                defense_bonus: 0,
+                power_bonus: 1,
+                attack_delay: 0,
+            });
+
+            // Return the object
+            object
+        }
+        Item::DigWand => {
+            // Create a wand of digging
+            let mut object = Object::new(x, y, '-', "wand of digging", SEPIA, false);
+            object.item = Some(Item::DigWand);
+
+            // Return the object
+            object
+        }
+        Item::Torch => {
+            // Create a torch, that lights the way until it burns out
+            let mut object = Object::new(x, y, '(', "torch", ORANGE, false);
+            object.item = Some(Item::Torch);
+            object.equipment = Some(Equipment {
+                equipped: false,
+                slot: Slot::Belt,
+                max_hp_bonus: 0,
+                defense_bonus: 0,
+                power_bonus: 0,
+                attack_delay: 0,
+            });
+            object.light = Some(Light {
+                radius: TORCH_RADIUS,
+                color: COLOR_TORCH_LIGHT,
+                fuel: Some(TORCH_FUEL),
+            });
+
+            // Return the object
+            object
+        }
+        Item::Lantern => {
+            // Create a lantern, brighter than a torch and never running out
+            let mut object = Object::new(x, y, '(', "lantern", LIGHT_YELLOW, false);
+            object.item = Some(Item::Lantern);
+            object.equipment = Some(Equipment {
+                equipped: false,
+                slot: Slot::Belt,
+                max_hp_bonus: 0,
+                defense_bonus: 0,
+                power_bonus: 0,
+                attack_delay: 0,
+            });
+            object.light = Some(Light {
+                radius: LANTERN_RADIUS,
+                color: COLOR_LANTERN_LIGHT,
+                fuel: None,
+            });
+
+            // Return the object
+            object
+        }
+        Item::Haste => {
+            // Create a potion of speed
+            let mut object = Object::new(x, y, '!', "potion of speed", LIGHT_CYAN, false);
+            object.item = Some(Item::Haste);
+
+            // Return the object
+            object
+        }
+        Item::Slow => {
+            // Create a scroll of slowness
+            let mut object = Object::new(x, y, '#', "scroll of slowness", LIGHT_YELLOW, false);
+            object.item = Some(Item::Slow);
+
+            // Return the object
+            object
+        }
+        Item::Crown => {
+            // Create the crown guarded by the boss, that wins the game when brought back to town
+            let mut object = Object::new(x, y, '&', "Crown of the Fallen Heroes", GOLD, false);
+            object.item = Some(Item::Crown);
+            object.equipment = Some(Equipment {
+                equipped: false,
+                slot: Slot::Head,
+                max_hp_bonus: 20,
+                defense_bonus: 2,
+                power_bonus: 0,
+                attack_delay: 0,
+            });
+
+            // Return the object
+            object
+        }
+        Item::Summon => {
+            // Create a scroll of summoning
+            let mut object = Object::new(x, y, '#', "scroll of summoning", LIGHT_YELLOW, false);
+            object.item = Some(Item::Summon);
+
+            // Return the object
+            object
+        }
+        Item::WarAxe => {
+            // Create a war axe, hitting hard but slow to swing
+            let mut object = Object::new(x, y, '/', "war axe", LIGHT_GREY, false);
+            object.item = Some(Item::WarAxe);
+            object.equipment = Some(Equipment {
+                equipped: false,
+                slot: Slot::RightHand,
+                max_hp_bonus: 0,
+                defense_bonus: 0,
+                power_bonus: 6,
+                attack_delay: 60,
+            });
+
+            // Return the object
+            object
+        }
+    };
+    
+    // Set item to be always visible once found
+    object.always_visible = true;
+
+    // Return the item
+    object
+}
+
+fn create_brazier(x: i32, y: i32) -> Object {
+    // Braziers burn forever, lighting up the room around them
+    let mut brazier = Object::new(x, y, '*', "brazier", FLAME, true);
+    brazier.always_visible = true;
+    brazier.light = Some(Light {
+        radius: BRAZIER_RADIUS,
+        color: FLAME,
+        fuel: None,
+    });
+
+    // Return the brazier
+    brazier
+}
+
+fn create_trap(kind: TrapKind, x: i32, y: i32) -> Object {
+    let (name, color) = match kind {
+        TrapKind::Dart => ("dart trap", LIGHT_GREY),
+        TrapKind::Teleport => ("teleport trap", LIGHT_VIOLET),
+        TrapKind::ConfusionGas => ("confusion gas trap", LIGHT_VIOLET),
+        TrapKind::PoisonGas => ("poison gas trap", LIGHT_GREEN),
+        TrapKind::Alarm => ("alarm trap", YELLOW),
+        TrapKind::Trapdoor => ("trapdoor", ORANGE),
+    };
+
+    // Traps don't block, and stay hidden until revealed
+    let mut trap = Object::new(x, y, '^', name, color, false);
+    trap.trap = Some(Trap {
+        kind,
+        revealed: false,
+    });
+
+    // Return the trap
+    trap
+}
+
+// Chance of a trapdoor, based on level. There's no level below the final depth to fall to.
+const TRAPDOOR_CHANCE: &[Transition] = &[
+    Transition { level: 3, value: 10 },
+    Transition { level: FINAL_DEPTH, value: 0 },
+];
+
+// Choose a random trap kind, based on level
+fn random_trap_kind(level: u32, rng: &mut StdRng) -> TrapKind {
+    // Create trap generator table
+    let trap_chances = &mut [
+        Weighted {
+            weight: 40,
+            item: TrapKind::Dart,
+        },
+        Weighted {
+            weight: 20,
+            item: TrapKind::Alarm,
+        },
+        Weighted {
+            weight: from_dungeon_level(
+                &[Transition { level: 2, value: 15 }],
+                level,
+            ),
+            item: TrapKind::Teleport,
+        },
+        Weighted {
+            weight: from_dungeon_level(
+                &[Transition { level: 2, value: 20 }],
+                level,
+            ),
+            item: TrapKind::ConfusionGas,
+        },
+        Weighted {
+            weight: from_dungeon_level(
+                &[Transition { level: 4, value: 15 }],
+                level,
+            ),
+            item: TrapKind::PoisonGas,
+        },
+        Weighted {
+            weight: from_dungeon_level(TRAPDOOR_CHANCE, level),
+            item: TrapKind::Trapdoor,
+        },
+    ];
+
+    // Create trap choice generator and return the chosen trap kind
+    let trap_choice = WeightedChoice::new(trap_chances);
+    trap_choice.sample(rng)
+}
+
+// Choose a random monster kind from the theme's monster pool, based on level
+fn random_monster_kind(level: u32, theme: Theme, rng: &mut StdRng) -> &'static str {
+    // Create monster generator table
+    let mut monster_chances: Vec<_> = theme
+        .data()
+        .monsters
+        .iter()
+        .map(|&(kind, table)| Weighted {
+            weight: from_dungeon_level(table, level),
+            item: kind,
+        })
+        .collect();
+
+    // Create monster choice generator
+    let monster_choice = WeightedChoice::new(&mut monster_chances);
+
+    // Return the chosen monster kind
+    monster_choice.sample(rng)
+}
+
+// Choose a random item, based on level and the theme's item weights
+fn random_item(level: u32, theme: Theme, rng: &mut StdRng) -> Item {
+    // Create item generator table
+    let item_chances = &mut [
+        Weighted {
+            weight: 35,
+            item: Item::Heal,
+        },
+        Weighted {
+            weight: from_dungeon_level(
+                &[Transition { level: 4, value: 25 }],
+                level,
+            ),
+            item: Item::Lightning,
+        },
+        Weighted {
+            weight: from_dungeon_level(
+                &[Transition { level: 6, value: 25 }],
+                level,
+            ),
+            item: Item::Fireball,
+        },
+        Weighted {
+            weight: from_dungeon_level(
+                &[Transition { level: 2, value: 10 }],
+                level,
+            ),
+            item: Item::Confuse,
+        },
+        Weighted {
+            weight: from_dungeon_level(
+                &[Transition { level: 4, value: 5 }],
+                level
+            ),
+            item: Item::Sword, 
+        },
+        Weighted {
+            weight: from_dungeon_level(
+                &[Transition { level: 8, value: 15 }],
+                level,
+            ),
+            item: Item::Shield, 
+        },
+        Weighted {
+            weight: from_dungeon_level(
+                &[Transition { level: 5, value: 5 }],
+                level,
+            ),
+            item: Item::WarAxe,
+        },
+        Weighted {
+            weight: from_dungeon_level(
+                &[Transition { level: 3, value: 10 }],
+                level,
+            ),
+            item: Item::Haste,
+        },
+        Weighted {
+            weight: from_dungeon_level(
+                &[Transition { level: 2, value: 10 }],
+                level,
+            ),
+            item: Item::Slow,
+        },
+        Weighted {
+            weight: from_dungeon_level(
+                &[Transition { level: 3, value: 8 }],
+                level,
+            ),
+            item: Item::Summon,
+        },
+        Weighted {
+            weight: from_dungeon_level(
+                &[Transition { level: 2, value: 5 }],
+                level,
+            ),
+            item: Item::Pickaxe,
+        },
+        Weighted {
+            weight: from_dungeon_level(
+                &[Transition { level: 3, value: 5 }],
+                level,
+            ),
+            item: Item::DigWand,
+        },
+        Weighted {
+            weight: 10,
+            item: Item::Torch,
+        },
+        Weighted {
+            weight: from_dungeon_level(
+                &[Transition { level: 3, value: 5 }],
+                level,
+            ),
+            item: Item::Lantern,
+        },
+    ];
+
+    // Apply the theme's item weights (as percentages of the base weights)
+    for chance in item_chances.iter_mut() {
+        if let Some(&(_, percent)) = theme.data().item_weights.iter().find(|&&(item, _)| item == chance.item) {
+            chance.weight = chance.weight * percent / 100;
+        }
+    }
+
+    // Create item choice generator
+    let item_choice = WeightedChoice::new(item_chances);
+
+    // Return the chosen item
+    item_choice.sample(rng)
+}
+
+fn place_objects(room: Rect, map: &Map, objects: &mut Vec<Object>, level: u32, theme: Theme, rng: &mut StdRng) {
+    // Define maximum number of monsters per room based on level
+    let max_monsters = from_dungeon_level(
+        &[
+            Transition { level: 1, value: 2 },
+            Transition { level: 4, value: 3 },
+            Transition { level: 6, value: 5 },
+        ],
+        level,
+    );
+    // Choose random number of monsters
+    let num_monsters = rng.gen_range(0, max_monsters + 1);
+
+    for _ in 0..num_monsters {
+        // Choose random spot for this monster
+        let x = rng.gen_range(room.x1 + 1, room.x2);
+        let y = rng.gen_range(room.y1 + 1, room.y2);
 
         // Check if the tile is not blocked
         if !is_blocked(x, y, map, objects) {
-            // Define chance of creating a troll based on level
-            let troll_chance = from_dungeon_level(
-                &[
-                    Transition { level: 3, value: 15 },
-                    Transition { level: 5, value: 30 },
-                    Transition { level: 7, value: 60 },
-                ],
-                level,
-            );
-            // Create monster generator table
-            let monster_chances = &mut [
-                Weighted {
-                    weight: 80,
-                    item: "orc",
-                },
-                Weighted {
-                    weight: troll_chance,
-                    item: "troll",
-                },
-            ];
-
-            // Create monster choice generator
-            let monster_choice = WeightedChoice::new(monster_chances);
-
-            // Generate the monster
-            let mut monster = match monster_choice.sample(&mut rand::thread_rng()) {
-                "orc" => {
-                    // Create an orc
-                    let mut object = Object::new(x, y, 'o', "orc", DESATURATED_GREEN, true);
-                    
-                    // Set orc components
-                    object.fighter = Some(Fighter {
-                        hp: 20,
-                        base_max_hp: 20,
-                        base_defense: 0,
-                        base_power: 4,
-                        xp: 35,
-                        on_death: DeathCallback::Monster,
-                    });
-                    object.ai = Some(AI::Basic);
-                    
-                    // Return the orc
-                    object
+            // Generate the monster and add it to objects list
+            let kind = random_monster_kind(level, theme, rng);
+            let mut monster = create_dungeon_monster(kind, x, y, level, rng);
+            roll_sleep(&mut monster, rng);
+            match PACKS.iter().find(|pack| pack.leader == kind) {
+                Some(pack) => place_pack(pack, monster, room, map, objects, level, rng),
+                None => objects.push(monster),
+            }
+        }
+    }
+
+    // Define maximum number of items per room based on level
+    let max_items = from_dungeon_level(
+        &[
+            Transition { level: 1, value: 1 },
+            Transition { level: 4, value: 2 },
+        ],
+        level,
+    );
+
+    // Choose random number of items
+    let num_items = rng.gen_range(0, max_items + 1);
+
+    for _ in 0..num_items {
+        // Choose random spot for this item
+        let x = rng.gen_range(room.x1 + 1, room.x2);
+        let y = rng.gen_range(room.y1 + 1, room.y2);
+
+        // Only place it if the tile is not blocked
+        if !is_blocked(x, y, map, objects) {
+            // Generate the item and add it to objects list
+            let item = create_item(random_item(level, theme, rng), x, y);
+            objects.push(item);
+        }
+    }
+
+    // Define chance of a trap in the room based on level
+    let trap_chance = from_dungeon_level(
+        &[
+            Transition { level: 1, value: 10 },
+            Transition { level: 3, value: 20 },
+            Transition { level: 6, value: 35 },
+        ],
+        level,
+    );
+
+    if rng.gen_range(0, 100) < trap_chance {
+        // Choose random spot for this trap
+        let x = rng.gen_range(room.x1 + 1, room.x2);
+        let y = rng.gen_range(room.y1 + 1, room.y2);
+
+        // Only place it on an empty tile, away from the center where the stairs and the
+        // player's arrival spot go
+        if (x, y) != room.center() && !objects.iter().any(|object| object.pos() == (x, y)) {
+            objects.push(create_trap(random_trap_kind(level, rng), x, y));
+        }
+    }
+}
+
+// Place a pack leader together with its followers, spread about the same room. The whole
+// pack is either asleep or awake.
+fn place_pack(
+    pack: &PackData,
+    leader: Object,
+    room: Rect,
+    map: &Map,
+    objects: &mut Vec<Object>,
+    level: u32,
+    rng: &mut StdRng,
+) {
+    let pack_id = objects.len() as u32;
+    let alertness = leader.fighter.map_or(Alertness::Wandering, |f| f.alertness);
+    let mut members = vec![leader];
+    for _ in 0..rng.gen_range(pack.min_size, pack.max_size + 1) {
+        let x = rng.gen_range(room.x1 + 1, room.x2);
+        let y = rng.gen_range(room.y1 + 1, room.y2);
+        if !is_blocked(x, y, map, objects) && !members.iter().any(|member| member.pos() == (x, y)) {
+            members.push(create_dungeon_monster(pack.follower, x, y, level, rng));
+        }
+    }
+
+    for (i, mut member) in members.into_iter().enumerate() {
+        if let Some(fighter) = member.fighter.as_mut() {
+            fighter.alertness = alertness;
+            fighter.pack = Some(Pack {
+                id: pack_id,
+                leader: i == 0,
+            });
+            if i > 0 {
+                fighter.flee_below = pack.follower_flee_below;
+            }
+        }
+        objects.push(member);
+    }
+}
+
+// Choose a random level theme, based on level
+fn random_theme(level: u32, rng: &mut StdRng) -> Theme {
+    // Create theme generator table
+    let mut theme_chances: Vec<_> = THEMES
+        .iter()
+        .map(|data| Weighted {
+            weight: from_dungeon_level(data.chance, level),
+            item: data.theme,
+        })
+        .collect();
+
+    // Create theme choice generator and return the chosen theme
+    let theme_choice = WeightedChoice::new(&mut theme_chances);
+    theme_choice.sample(rng)
+}
+
+// Choose a random prefab that may appear on the given level
+fn random_prefab(level: u32, rng: &mut StdRng) -> Option<&'static Prefab> {
+    // Create prefab generator table from the prefabs allowed at this depth
+    let mut prefab_chances: Vec<_> = PREFABS
+        .iter()
+        .filter(|prefab| level >= prefab.min_level && level <= prefab.max_level)
+        .map(|prefab| Weighted {
+            weight: prefab.rarity,
+            item: prefab,
+        })
+        .collect();
+
+    // No prefab can appear on this level
+    if prefab_chances.is_empty() {
+        return None;
+    }
+
+    // Create prefab choice generator and return the chosen prefab
+    let prefab_choice = WeightedChoice::new(&mut prefab_chances);
+    Some(prefab_choice.sample(rng))
+}
+
+// Check if the prefab (plus a one tile margin) only covers untouched walls
+fn prefab_fits(prefab: &Prefab, x: i32, y: i32, map: &Map) -> bool {
+    // Keep the outer boundary of the map intact
+    if x < 2 || y < 2 || x + prefab.width() + 2 > MAP_WIDTH || y + prefab.height() + 2 > MAP_HEIGHT {
+        return false;
+    }
+
+    // Every tile, including the margin, must still be a wall
+    for px in (x - 1)..(x + prefab.width() + 1) {
+        for py in (y - 1)..(y + prefab.height() + 1) {
+            if !map[px as usize][py as usize].blocked {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+// Stamp the prefab onto the map, spawning the monsters and items of its legend.
+// Returns the doors as (x, y, dx, dy), where (dx, dy) points out of the prefab.
+fn stamp_prefab(
+    prefab: &Prefab,
+    x: i32,
+    y: i32,
+    map: &mut Map,
+    objects: &mut Vec<Object>,
+    context: PrefabContext,
+    rng: &mut StdRng,
+) -> Vec<(i32, i32, i32, i32)> {
+    let PrefabContext { level, theme } = context;
+    let mut doors = vec![];
+
+    for (py, row) in prefab.layout.iter().enumerate() {
+        for (px, glyph) in row.chars().enumerate() {
+            let (px, py) = (px as i32, py as i32);
+            let (tile_x, tile_y) = (x + px, y + py);
+
+            // Carve the tile, everything except walls is passable
+            map[tile_x as usize][tile_y as usize] = match glyph {
+                '#' => Tile::wall(),
+                '+' => Tile::door(),
+                _ => Tile::empty(),
+            };
+
+            // Populate the tile according to the legend
+            match glyph {
+                '+' => {
+                    // Doors point away from the edge they are on
+                    let dx = if px == 0 { -1 } else if px == prefab.width() - 1 { 1 } else { 0 };
+                    let dy = if py == 0 { -1 } else if py == prefab.height() - 1 { 1 } else { 0 };
+                    doors.push((tile_x, tile_y, dx, dy));
                 }
-                "troll" => {
-                    // Create a troll
-                    let mut object = Object::new(x, y, 'T', "troll", DARKER_GREEN, true);
-
-                    // Set troll components
-                    object.fighter = Some(Fighter {
-                        hp: 30,
-                        base_max_hp: 30,
-                        base_defense: 2,
-                        base_power: 8,
-                        xp: 100,
-                        on_death: DeathCallback::Monster,
-                    });
-                    object.ai = Some(AI::Basic);
+                'o' | 'T' => {
+                    let kind = if glyph == 'o' { "orc" } else { "troll" };
+                    // Monsters in prefabs guard them
+                    let mut monster = create_dungeon_monster(kind, tile_x, tile_y, level, rng);
+                    roll_sleep(&mut monster, rng);
+                    if let Some(fighter) = monster.fighter.as_mut() {
+                        fighter.guard_post = Some((tile_x, tile_y));
+                    }
+                    objects.push(monster);
+                }
+                '!' => objects.push(create_item(Item::Heal, tile_x, tile_y)),
+                '/' => objects.push(create_item(Item::Sword, tile_x, tile_y)),
+                '[' => objects.push(create_item(Item::Shield, tile_x, tile_y)),
+                '?' => objects.push(create_item(random_item(level, theme, rng), tile_x, tile_y)),
+                '*' => objects.push(create_brazier(tile_x, tile_y)),
+                _ => {}
+            }
+        }
+    }
+
+    // Return the doors of the prefab
+    doors
+}
+
+// Connect a prefab door to the closest room lying in the direction it faces, tunnelling
+// around the prefabs stamped so far. A door with no such room is walled up instead.
+fn connect_door(door: (i32, i32, i32, i32), rooms: &[Rect], vaults: &[Rect], map: &mut Map) {
+    // Start the tunnel on the tile just outside the door
+    let (door_x, door_y, dx, dy) = door;
+    let (out_x, out_y) = (door_x + dx, door_y + dy);
+    let distance = |&(x, y): &(i32, i32)| (x - out_x).pow(2) + (y - out_y).pow(2);
+
+    // Only rooms in front of the door will do, so the tunnel never crosses the prefab
+    let path = rooms
+        .iter()
+        .map(|room| room.center())
+        .filter(|&(x, y)| (x - out_x) * dx + (y - out_y) * dy >= 0)
+        .min_by_key(distance)
+        .and_then(|room| tunnel_path((out_x, out_y), room, vaults, map));
+
+    match path {
+        Some(path) => {
+            for (x, y) in path {
+                map[x as usize][y as usize] = Tile::empty();
+            }
+        }
+        None => map[door_x as usize][door_y as usize] = Tile::wall(),
+    }
+}
+
+// Find the shortest way to dig from one position to another, moving straight only and
+// never entering a prefab or the outer boundary of the map
+fn tunnel_path(from: (i32, i32), to: (i32, i32), vaults: &[Rect], map: &Map) -> Option<Vec<(i32, i32)>> {
+    let (map_width, map_height) = map_size(map);
+    let can_dig = |(x, y): (i32, i32)| {
+        x > 0 && y > 0 && x < map_width - 1 && y < map_height - 1 && !vaults.iter().any(|vault| vault.contains(x, y))
+    };
+    if !can_dig(from) {
+        return None;
+    }
+
+    // Breadth-first search, remembering where each position was reached from
+    let mut came_from = vec![vec![None; map_height as usize]; map_width as usize];
+    let mut frontier = VecDeque::new();
+    came_from[from.0 as usize][from.1 as usize] = Some(from);
+    frontier.push_back(from);
+    while let Some((x, y)) = frontier.pop_front() {
+        if (x, y) == to {
+            // Walk back to the start to build the path
+            let mut path = vec![to];
+            let mut current = to;
+            while current != from {
+                current = came_from[current.0 as usize][current.1 as usize]?;
+                path.push(current);
+            }
+            return Some(path);
+        }
+        for &(dx, dy) in &[(1, 0), (-1, 0), (0, 1), (0, -1)] {
+            let next = (x + dx, y + dy);
+            if can_dig(next) && came_from[next.0 as usize][next.1 as usize].is_none() {
+                came_from[next.0 as usize][next.1 as usize] = Some((x, y));
+                frontier.push_back(next);
+            }
+        }
+    }
+    None
+}
+
+// Try to find free space for the prefab, then stamp it and connect it to the level.
+// The space it takes up is added to the vaults, so later tunnels go around it.
+fn place_prefab(
+    prefab: &Prefab,
+    rooms: &[Rect],
+    vaults: &mut Vec<Rect>,
+    map: &mut Map,
+    objects: &mut Vec<Object>,
+    context: PrefabContext,
+    rng: &mut StdRng,
+) {
+    for _ in 0..PREFAB_PLACEMENT_ATTEMPTS {
+        // Generate random position without going out of the boundaries of the map
+        let x = rng.gen_range(0, MAP_WIDTH - prefab.width());
+        let y = rng.gen_range(0, MAP_HEIGHT - prefab.height());
+
+        if prefab_fits(prefab, x, y, map) {
+            // Stamp the prefab, then tunnel from each of its doors
+            let doors = stamp_prefab(prefab, x, y, map, objects, context, rng);
+            vaults.push(Rect::new(x, y, prefab.width() - 1, prefab.height() - 1));
+            for door in doors {
+                connect_door(door, rooms, vaults, map);
+            }
+            return;
+        }
+    }
+}
+
+// Check if a position can be walked to from another, through passable tiles
+fn is_reachable(from: (i32, i32), to: (i32, i32), map: &Map) -> bool {
+    let (map_width, map_height) = map_size(map);
+    let mut visited = vec![vec![false; map_height as usize]; map_width as usize];
+    let mut frontier = VecDeque::new();
+
+    // Flood fill from the starting position, in all 8 directions
+    visited[from.0 as usize][from.1 as usize] = true;
+    frontier.push_back(from);
+    while let Some((x, y)) = frontier.pop_front() {
+        if (x, y) == to {
+            return true;
+        }
+        for dx in -1..2 {
+            for dy in -1..2 {
+                let (nx, ny) = (x + dx, y + dy);
+                if nx < 0 || ny < 0 || nx >= map_width || ny >= map_height {
+                    continue;
+                }
+                let tile = &map[nx as usize][ny as usize];
+                if !tile.blocked && !visited[nx as usize][ny as usize] {
+                    visited[nx as usize][ny as usize] = true;
+                    frontier.push_back((nx, ny));
+                }
+            }
+        }
+    }
+    false
+}
+
+// Put doors in the doorways (where tunnels enter rooms), hiding some behind secret doors
+// as long as the stairs stay reachable from the player's starting position
+fn place_doors(
+    rooms: &[Rect],
+    start: (i32, i32),
+    stairs: (i32, i32),
+    map: &mut Map,
+    level: u32,
+    rng: &mut StdRng,
+) {
+    // Define maximum number of secret doors based on level
+    let max_secret_doors = from_dungeon_level(
+        &[
+            Transition { level: 1, value: 1 },
+            Transition { level: 3, value: 2 },
+            Transition { level: 6, value: 4 },
+        ],
+        level,
+    );
+
+    // Find doorways: passable tiles on a room's walls, between two walls
+    let is_wall = |map: &Map, x: i32, y: i32| map[x as usize][y as usize].blocked;
+    let mut doorways = vec![];
+    for room in rooms {
+        for x in room.x1..(room.x2 + 1) {
+            for y in room.y1..(room.y2 + 1) {
+                let on_wall = x == room.x1 || x == room.x2 || y == room.y1 || y == room.y2;
+                if !on_wall || is_wall(map, x, y) {
+                    continue;
+                }
+                let horizontal = is_wall(map, x, y - 1) && is_wall(map, x, y + 1)
+                    && !is_wall(map, x - 1, y) && !is_wall(map, x + 1, y);
+                let vertical = is_wall(map, x - 1, y) && is_wall(map, x + 1, y)
+                    && !is_wall(map, x, y - 1) && !is_wall(map, x, y + 1);
+                if horizontal || vertical {
+                    doorways.push((x, y));
+                }
+            }
+        }
+    }
+
+    // Hide random doorways, keeping only the ones that don't cut off the stairs.
+    // The others get plain doors.
+    rng.shuffle(&mut doorways);
+    let mut num_secret_doors = 0;
+    for (x, y) in doorways {
+        if num_secret_doors < max_secret_doors {
+            map[x as usize][y as usize] = Tile::secret_door();
+            if is_reachable(start, stairs, map) {
+                num_secret_doors += 1;
+                continue;
+            }
+        }
+        map[x as usize][y as usize] = Tile::door();
+    }
+}
+
+fn make_map(objects: &mut Vec<Object>, level: u32, theme: Theme, rng: &mut StdRng) -> Map {
+    // Get the generator parameters of the theme
+    let theme_data = theme.data();
+
+    // Fill map with "blocked" tiles
+    let mut map = vec![vec![Tile::wall(); MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+
+    // Player is the first element, remove everything else.
+    // NOTE: works only when the player is the first object!
+    assert_eq!(&objects[PLAYER] as *const _, &objects[0] as *const _);
+    objects.truncate(1);
+
+    // Create rooms vector, and the rooms that are lit (fewer the deeper the level)
+    let mut rooms = vec![];
+    let mut lit_rooms = vec![];
+    let lit_chance = from_dungeon_level(
+        &[
+            Transition { level: 1, value: 80 },
+            Transition { level: 3, value: 50 },
+            Transition { level: 6, value: 25 },
+        ],
+        level,
+    );
+
+    // Generate rooms
+    for _ in 0..theme_data.max_rooms {
+        // Generate random width and height for new room
+        let w = rng.gen_range(theme_data.room_min_size, theme_data.room_max_size + 1);
+        let h = rng.gen_range(theme_data.room_min_size, theme_data.room_max_size + 1);
+        
+        // Generate random position without going out of the boundaries of the map
+        let x = rng.gen_range(0, MAP_WIDTH - w);
+        let y = rng.gen_range(0, MAP_HEIGHT - h);
+
+        // Create new room
+        let new_room = Rect::new(x, y, w, h);
+
+        // Run through the other rooms and see if they intersect with this one
+        let failed = rooms.iter().any(|other_room| new_room.intersects_with(other_room));
+
+        if !failed {
+            // This means there are no intersections, so this room is valid
+            // "carve" it to the map's wall tiles
+            create_room(new_room, &mut map);
+
+            // Cover some of its ground in flammable debris
+            scatter_debris(new_room, &mut map, theme_data.debris_chance, rng);
+
+            // Add some content to this room, such as monsters
+            place_objects(new_room, &map, objects, level, theme, rng);
+
+            // Light the room, or maybe light a brazier in the dark
+            if rng.gen_range(0, 100) < lit_chance {
+                lit_rooms.push(new_room);
+            } else if rng.gen_range(0, 100) < BRAZIER_CHANCE {
+                let x = rng.gen_range(new_room.x1 + 1, new_room.x2);
+                let y = rng.gen_range(new_room.y1 + 1, new_room.y2);
+                if !objects.iter().any(|object| object.pos() == (x, y)) {
+                    objects.push(create_brazier(x, y));
+                }
+            }
+
+            // Center coordinates of the new room, will be useful later
+            let (new_x, new_y) = new_room.center();
+
+            if rooms.is_empty() {
+                // This is the first room, where the player starts at
+                objects[PLAYER].set_pos(new_x, new_y);
+
+                // Create up stairs where the player arrives, leading back to the previous level
+                let mut up_stairs = Object::new(new_x, new_y, '>', "up stairs", WHITE, false);
+                up_stairs.always_visible = true;
+                objects.push(up_stairs);
+            }  else {
+                // All rooms after the first:
+                // connect it to the previous room with a tunnel
+                // center coordinates of the previous room
+                let (prev_x, prev_y) = rooms[rooms.len() - 1].center();
+            
+                // Toss a coin (random bool value -- either true or false)
+                if rng.gen() {
+                    // First move horizontally, then vertically
+                    create_h_tunnel(prev_x, new_x, prev_y, &mut map);
+                    create_v_tunnel(prev_y, new_y, new_x, &mut map);
+                } else {
+                    // First move vertically, then horizontally
+                    create_v_tunnel(prev_y, new_y, prev_x, &mut map);
+                    create_h_tunnel(prev_x, new_x, new_y, &mut map);
+                }
+            }
+
+            // Finally, append the new room to the list
+            rooms.push(new_room);
+        }
+    }
+
+    // Stamp some prefabs into the free space left between the rooms
+    let mut vaults = vec![];
+    for _ in 0..MAX_PREFABS {
+        if let Some(prefab) = random_prefab(level, rng) {
+            place_prefab(prefab, &rooms, &mut vaults, &mut map, objects, PrefabContext { level, theme }, rng);
+        }
+    }
+
+    // Light up the lit rooms, walls included, now that all the tunnels have been dug through them
+    for room in lit_rooms {
+        for x in room.x1..=room.x2 {
+            for y in room.y1..=room.y2 {
+                map[x as usize][y as usize].lit = true;
+            }
+        }
+    }
+
+    // Create stairs at the center of the last room, or on the final depth, the crown and
+    // the boss guarding it
+    let (last_room_x, last_room_y) = rooms[rooms.len() - 1].center();
+    if level == FINAL_DEPTH {
+        place_boss(last_room_x, last_room_y, &map, objects);
+    } else {
+        let mut stairs = Object::new(last_room_x, last_room_y, '<', "stairs", WHITE, false);
+        stairs.always_visible = true;
+        objects.push(stairs);
+    }
+
+    // Put doors in the tunnel connections, hiding some of them behind secret doors
+    let start = objects[PLAYER].pos();
+    place_doors(&rooms, start, (last_room_x, last_room_y), &mut map, level, rng);
+
+    // Return the map
+    map
+}
+
+// Place the crown at the given position, with the boss standing guard right next to it
+fn place_boss(x: i32, y: i32, map: &Map, objects: &mut Vec<Object>) {
+    objects.push(create_item(Item::Crown, x, y));
+    if let Some((boss_x, boss_y)) = free_tile_near(x, y, map, objects) {
+        let mut boss = create_monster(BOSS_KIND, boss_x, boss_y);
+        if let Some(fighter) = boss.fighter.as_mut() {
+            fighter.guard_post = Some((boss_x, boss_y));
+        }
+        objects.push(boss);
+    }
+}
+
+// Build the surface town from its hand-designed layout
+fn make_town(objects: &mut Vec<Object>) -> Map {
+    // Fill map with "blocked" tiles, the size of the layout
+    let width = TOWN_LAYOUT[0].len();
+    let mut map = vec![vec![Tile::wall(); TOWN_LAYOUT.len()]; width];
+
+    // Player is the first element, remove everything else.
+    // NOTE: works only when the player is the first object!
+    assert_eq!(&objects[PLAYER] as *const _, &objects[0] as *const _);
+    objects.truncate(1);
+
+    for (y, row) in TOWN_LAYOUT.iter().enumerate() {
+        for (x, glyph) in row.chars().enumerate() {
+            // Carve the tile, everything except walls is passable
+            if glyph != '#' {
+                map[x][y] = Tile::empty();
+            }
+
+            // Populate the tile according to the legend
+            let (x, y) = (x as i32, y as i32);
+            match glyph {
+                '@' => objects[PLAYER].set_pos(x, y),
+                '<' => {
+                    // The dungeon entrance
+                    let mut stairs = Object::new(x, y, '<', "stairs", WHITE, false);
+                    stairs.always_visible = true;
+                    objects.push(stairs);
+                }
+                'A' => {
+                    // The alchemist sells potions and scrolls
+                    let mut alchemist = Object::new(x, y, '@', "alchemist", LIGHT_VIOLET, true);
+                    alchemist.npc = Some(Npc::Shopkeeper {
+                        stock: vec![
+                            Item::Heal,
+                            Item::Confuse,
+                            Item::Lightning,
+                            Item::Fireball,
+                            Item::DigWand,
+                            Item::Haste,
+                            Item::Slow,
+                            Item::Summon,
+                        ],
+                    });
+                    objects.push(alchemist);
+                }
+                'W' => {
+                    // The armourer sells weapons and armour
+                    let mut armourer = Object::new(x, y, '@', "armourer", SKY, true);
+                    armourer.npc = Some(Npc::Shopkeeper {
+                        stock: vec![
+                            Item::Sword,
+                            Item::WarAxe,
+                            Item::Shield,
+                            Item::Pickaxe,
+                            Item::Torch,
+                            Item::Lantern,
+                        ],
+                    });
+                    objects.push(armourer);
+                }
+                'H' => {
+                    // The healer tends the player's wounds
+                    let mut healer = Object::new(x, y, '@', "healer", LIGHT_GREEN, true);
+                    healer.npc = Some(Npc::Healer);
+                    objects.push(healer);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // The town lies under the open sky, so it's lit everywhere
+    for tile in map.iter_mut().flatten() {
+        tile.lit = true;
+    }
+
+    // Return the map
+    map
+}
+
+/// Generate a level (the town for level 0) from a seed, returning its map and theme
+fn generate_level(objects: &mut Vec<Object>, level: u32, seed: u64) -> (Map, Theme) {
+    if level == 0 {
+        return (make_town(objects), Theme::Standard);
+    }
+
+    // The same seed always generates the same level
+    let mut rng = StdRng::seed_from_u64(seed);
+    let theme = random_theme(level, &mut rng);
+    let map = make_map(objects, level, theme, &mut rng);
+    (map, theme)
+}
+
+fn is_blocked(x: i32, y: i32, map: &Map, objects: &[Object]) -> bool {
+    // First test the map tile
+    if map[x as usize][y as usize].blocked {
+        return true;
+    }
+    // Now check for any blocking objects
+    objects.iter().any(|object| object.blocks && object.pos() == (x, y))
+}
+
+// Move object by the given amount, if the destination is not blocked
+fn move_by(id: usize, dx: i32, dy: i32, map: &Map, objects: &mut [Object]) {
+    let (x, y) = objects[id].pos();
+    if !is_blocked(x + dx, y + dy, map, objects) {
+        objects[id].set_pos(x + dx, y + dy);
+    }
+}
+
+fn move_towards(id: usize, target_x: i32, target_y: i32, map: &Map, objects: &mut [Object]) {
+    // Vector from this object to the target, and distance
+    let dx = target_x - objects[id].x;
+    let dy = target_y - objects[id].y;
+    let distance = ((dx.pow(2) + dy.pow(2)) as f32).sqrt();
+
+    // Normalize it to length 1 (preserving direction), then round it and
+    // convert to integer so the movement is restricted to the map grid
+    let dx = (dx as f32 / distance).round() as i32;
+    let dy = (dy as f32 / distance).round() as i32;
+
+    // Monsters know where the traps of their level are, and step around them
+    let (x, y) = (objects[id].x + dx, objects[id].y + dy);
+    if trap_at(x, y, objects).is_some() {
+        return;
+    }
+
+    // Move object
+    move_by(id, dx, dy, map, objects);
+}
+
+/// Find a path between two positions with A*, returning the steps to take (without the start).
+/// `cost` returns the cost of stepping onto a tile, or None if it can't be entered;
+/// the destination itself can always be entered.
+fn find_path<F>(from: (i32, i32), to: (i32, i32), map: &Map, cost: F) -> Option<Vec<(i32, i32)>>
+where
+    F: Fn(i32, i32) -> Option<i32>,
+{
+    let (map_width, map_height) = map_size(map);
+    let index = |(x, y): (i32, i32)| (x + y * map_width) as usize;
+
+    // Moving diagonally costs the same as moving straight,
+    // so the distance is the largest of the two axes
+    let heuristic = |(x, y): (i32, i32)| cmp::max((to.0 - x).abs(), (to.1 - y).abs());
+
+    let mut best_cost = vec![i32::MAX; (map_width * map_height) as usize];
+    let mut came_from = vec![None; (map_width * map_height) as usize];
+    let mut open = BinaryHeap::new();
+    best_cost[index(from)] = 0;
+    open.push(Reverse((heuristic(from), 0, from)));
+
+    let mut expanded = 0;
+    while let Some(Reverse((_, cost_so_far, position))) = open.pop() {
+        if position == to {
+            // Walk back from the destination to build the path
+            let mut path = vec![position];
+            let mut current = position;
+            while let Some(previous) = came_from[index(current)] {
+                if previous == from {
+                    break;
+                }
+                path.push(previous);
+                current = previous;
+            }
+            path.reverse();
+            return Some(path);
+        }
+
+        // Skip outdated entries, and give up on paths that are too long to search
+        if cost_so_far > best_cost[index(position)] {
+            continue;
+        }
+        expanded += 1;
+        if expanded > MAX_PATH_NODES {
+            return None;
+        }
+
+        // Explore all 8 neighbours
+        for dx in -1..2 {
+            for dy in -1..2 {
+                let next = (position.0 + dx, position.1 + dy);
+                if (dx, dy) == (0, 0) || next.0 < 0 || next.1 < 0 || next.0 >= map_width || next.1 >= map_height {
+                    continue;
+                }
+                let step_cost = if next == to {
+                    Some(1)
+                } else {
+                    cost(next.0, next.1)
+                };
+                if let Some(step_cost) = step_cost {
+                    let next_cost = cost_so_far + step_cost;
+                    if next_cost < best_cost[index(next)] {
+                        best_cost[index(next)] = next_cost;
+                        came_from[index(next)] = Some(position);
+                        open.push(Reverse((next_cost + heuristic(next), next_cost, next)));
+                    }
+                }
+            }
+        }
+    }
+
+    // No path exists
+    None
+}
+
+// Move a monster one step along a path to the target, recomputing the path when needed.
+// Other monsters are soft obstacles, so groups route around each other.
+fn move_astar(id: usize, target_x: i32, target_y: i32, map: &Map, objects: &mut [Object]) {
+    // Check if the cached path still leads to the target, and its next step is free
+    let target = (target_x, target_y);
+    let path_valid = objects[id].path.last() == Some(&target)
+        && objects[id]
+            .path
+            .first()
+            .map_or(false, |&(x, y)| !is_blocked(x, y, map, objects));
+
+    if !path_valid {
+        // Recompute the path around walls, traps and other monsters
+        let from = objects[id].pos();
+        let path = find_path(from, target, map, |x, y| {
+            if map[x as usize][y as usize].blocked || trap_at(x, y, objects).is_some() {
+                None
+            } else if objects.iter().any(|object| object.blocks && object.pos() == (x, y)) {
+                Some(1 + MONSTER_PATH_COST)
+            } else {
+                Some(1)
+            }
+        });
+        objects[id].path = path.unwrap_or_default();
+    }
+
+    match objects[id].path.first().cloned() {
+        Some((x, y)) if !is_blocked(x, y, map, objects) => {
+            // Take the next step
+            objects[id].set_pos(x, y);
+            objects[id].path.remove(0);
+        }
+        Some(_) => {
+            // Someone is in the way, wait and find another path next turn
+            objects[id].path.clear();
+        }
+        None => {
+            // No path, just try to get closer
+            move_towards(id, target_x, target_y, map, objects);
+        }
+    }
+}
+
+impl DijkstraMap {
+    /// Build a distance map spreading out from the sources, through tiles where `passable` is true.
+    pub fn new<F>(map: &Map, sources: &[(i32, i32)], passable: F) -> Self
+    where
+        F: Fn(i32, i32) -> bool,
+    {
+        DijkstraMap::bounded(map, sources, i32::MAX, passable)
+    }
+
+    /// Like `new`, but stop spreading past `max_distance`, leaving anything further out unreached.
+    pub fn bounded<F>(map: &Map, sources: &[(i32, i32)], max_distance: i32, passable: F) -> Self
+    where
+        F: Fn(i32, i32) -> bool,
+    {
+        let (map_width, map_height) = map_size(map);
+        let mut dijkstra = DijkstraMap {
+            width: map_width,
+            distances: vec![i32::MAX; (map_width * map_height) as usize],
+        };
+        for &(x, y) in sources {
+            dijkstra.distances[(x + y * map_width) as usize] = 0;
+        }
+        dijkstra.relax(map, max_distance, passable);
+        dijkstra
+    }
+
+    /// Turn a distance map into one leading away from its sources. Scaling the distances
+    /// past -1 before letting them settle again makes fleeing monsters head for open ground
+    /// rather than backing themselves into a dead end.
+    pub fn flee<F>(&self, map: &Map, passable: F) -> Self
+    where
+        F: Fn(i32, i32) -> bool,
+    {
+        let mut flee = DijkstraMap {
+            width: self.width,
+            distances: self
+                .distances
+                .iter()
+                .map(|&distance| if distance == i32::MAX { distance } else { -distance * 6 / 5 })
+                .collect(),
+        };
+        flee.relax(map, i32::MAX, passable);
+        flee
+    }
+
+    // Return the distance at a position, or i32::MAX if it can't be reached
+    pub fn distance(&self, x: i32, y: i32) -> i32 {
+        if x < 0 || x >= self.width {
+            return i32::MAX;
+        }
+        self.distances.get((x + y * self.width) as usize).cloned().unwrap_or(i32::MAX)
+    }
+
+    // Spread the distances out until no passable tile within `max_distance` is more than a step
+    // above its lowest neighbour
+    fn relax<F>(&mut self, map: &Map, max_distance: i32, passable: F)
+    where
+        F: Fn(i32, i32) -> bool,
+    {
+        let (map_width, map_height) = map_size(map);
+        let mut open = BinaryHeap::new();
+        for x in 0..map_width {
+            for y in 0..map_height {
+                let distance = self.distance(x, y);
+                if distance != i32::MAX {
+                    open.push(Reverse((distance, (x, y))));
+                }
+            }
+        }
+
+        while let Some(Reverse((distance, (x, y)))) = open.pop() {
+            // Skip outdated entries, and stop at the edge of the area to cover
+            if distance > self.distance(x, y) || distance >= max_distance {
+                continue;
+            }
+
+            // Explore all 8 neighbours
+            for dx in -1..2 {
+                for dy in -1..2 {
+                    let (next_x, next_y) = (x + dx, y + dy);
+                    if (dx, dy) == (0, 0)
+                        || next_x < 0
+                        || next_y < 0
+                        || next_x >= map_width
+                        || next_y >= map_height
+                        || !passable(next_x, next_y)
+                    {
+                        continue;
+                    }
+                    if distance + 1 < self.distance(next_x, next_y) {
+                        self.distances[(next_x + next_y * map_width) as usize] = distance + 1;
+                        open.push(Reverse((distance + 1, (next_x, next_y))));
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Recompute the distance maps around the player, if anything they depend on has changed
+fn update_player_maps(game: &mut Game, objects: &[Object]) {
+    let key = (game.dungeon_level, game.map_revision, objects[PLAYER].pos());
+    if game.dijkstra.player_key == Some(key) {
+        return;
+    }
+
+    let map = &game.map;
+    let passable = |x: i32, y: i32| !map[x as usize][y as usize].blocked;
+    let approach = DijkstraMap::new(map, &[objects[PLAYER].pos()], passable);
+    game.dijkstra.flee = approach.flee(map, passable);
+    game.dijkstra.approach = approach;
+    game.dijkstra.player_key = Some(key);
+}
+
+// Recompute the distance map towards unexplored tiles, if the player has learned anything new
+fn update_explore_map(game: &mut Game, objects: &[Object]) {
+    let explored = game.map.iter().flatten().filter(|tile| tile.explored).count();
+    let revealed_traps = objects.iter().filter(|object| object.trap.map_or(false, |t| t.revealed)).count();
+    let key = (game.dungeon_level, game.map_revision, explored, revealed_traps);
+    if game.dijkstra.explore_key == Some(key) {
+        return;
+    }
+
+    // Head for the frontier: unexplored open tiles right next to explored open ones, so solid
+    // rock the player can never see isn't a goal
+    let map = &game.map;
+    let (map_width, map_height) = map_size(map);
+    let is_open = |x: i32, y: i32, explored: bool| {
+        x >= 0 && y >= 0 && x < map_width && y < map_height && {
+            let tile = &map[x as usize][y as usize];
+            tile.explored == explored && !tile.blocked
+        }
+    };
+    let frontier: Vec<_> = (0..map_width)
+        .flat_map(|x| (0..map_height).map(move |y| (x, y)))
+        .filter(|&(x, y)| {
+            is_open(x, y, false) && (-1..=1).any(|dx| (-1..=1).any(|dy| is_open(x + dx, y + dy, true)))
+        })
+        .collect();
+
+    // Walk only through tiles the player knows to be clear, around the traps they've found
+    game.dijkstra.explore = DijkstraMap::new(map, &frontier, |x, y| {
+        let tile = &map[x as usize][y as usize];
+        tile.explored && !tile.blocked && !is_known_trap(PLAYER, x, y, objects)
+    });
+    game.dijkstra.explore_key = Some(key);
+}
+
+// Check if there's a trap at a position that the given object knows about.
+// Monsters know where all the traps are, the player only those they've found.
+fn is_known_trap(id: usize, x: i32, y: i32, objects: &[Object]) -> bool {
+    trap_at(x, y, objects).map_or(false, |trap_id| {
+        id != PLAYER || objects[trap_id].trap.map_or(false, |t| t.revealed)
+    })
+}
+
+// Move an object to the free neighbouring tile scoring lowest, if that beats staying put.
+// Returns true if it moved.
+fn move_downhill<F>(id: usize, map: &Map, objects: &mut [Object], score: F) -> bool
+where
+    F: Fn(i32, i32) -> i32,
+{
+    let (map_width, map_height) = map_size(map);
+    let (x, y) = objects[id].pos();
+    let mut best = (score(x, y), (x, y));
+    for dx in -1..2 {
+        for dy in -1..2 {
+            let (next_x, next_y) = (x + dx, y + dy);
+            if next_x < 0
+                || next_y < 0
+                || next_x >= map_width
+                || next_y >= map_height
+                || is_blocked(next_x, next_y, map, objects)
+                || is_known_trap(id, next_x, next_y, objects)
+            {
+                continue;
+            }
+            let next_score = score(next_x, next_y);
+            if next_score < best.0 {
+                best = (next_score, (next_x, next_y));
+            }
+        }
+    }
+
+    let (best_x, best_y) = best.1;
+    if (best_x, best_y) == (x, y) {
+        return false;
+    }
+    objects[id].set_pos(best_x, best_y);
+    true
+}
+
+fn player_move_or_attack(dx: i32, dy: i32, tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) {
+    // A confused player stumbles in a random direction, never staying in place
+    let (dx, dy) = if objects[PLAYER].fighter.map_or(false, |f| f.confused_turns > 0) {
+        let mut rng = rand::thread_rng();
+        loop {
+            let step = (rng.gen_range(-1, 2), rng.gen_range(-1, 2));
+            if step != (0, 0) {
+                break step;
+            }
+        }
+    } else {
+        (dx, dy)
+    };
+
+    // The coordinates the player is moving to/attacking
+    let x = objects[PLAYER].x + dx;
+    let y = objects[PLAYER].y + dy;
+
+    // Talk to a non-hostile character instead of attacking it
+    let npc_id = objects.iter().position(|object| object.npc.is_some() && object.pos() == (x, y));
+    if let Some(npc_id) = npc_id {
+        interact_with_npc(npc_id, tcod, game, objects);
+        return;
+    }
+
+    // Try to find an attackable object there
+    let target_id = objects.iter().position(|object| object.fighter.is_some() && object.pos() == (x, y));
+
+    // Dig into walls when wielding a pickaxe
+    let is_wall = game.map[x as usize][y as usize].blocked;
+    if target_id.is_none() && is_wall && is_wielding(Item::Pickaxe, &game.inventory) {
+        dig_with_pickaxe(x, y, tcod, game, objects);
+        return;
+    }
+
+    // Attack if target found, move otherwise
+    match target_id {
+        Some(target_id)
+            if target_id != PLAYER && objects[target_id].fighter.map_or(false, |f| f.faction == Faction::Player) =>
+        {
+            // Swap places with an ally
+            let (player_x, player_y) = objects[PLAYER].pos();
+            objects[target_id].set_pos(player_x, player_y);
+            objects[PLAYER].set_pos(x, y);
+        }
+        Some(target_id) if target_id != PLAYER => {
+            // Attack the target
+            let (player, target) = mut_two(PLAYER, target_id, objects);
+            player.attack(target, game);
+            make_noise(Noise::Combat, x, y, tcod, game, objects);
+        }
+        _ => {
+            // Move the player
+            move_by(PLAYER, dx, dy, &game.map, objects);
+        }
+    }
+}
+
+// Change a map tile, keeping the FOV map in sync with it
+fn set_tile(x: i32, y: i32, tile: Tile, tcod: &mut Tcod, game: &mut Game) {
+    let map = &mut game.map;
+    // Keep whether the player has already seen the tile, and whether it's lit
+    let Tile { explored, lit, .. } = map[x as usize][y as usize];
+    map[x as usize][y as usize] = Tile { explored, lit, ..tile };
+    tcod.fov.set(x, y, !tile.block_sight, !tile.blocked);
+    tcod.light_fov.set(x, y, !tile.block_sight, !tile.blocked);
+    game.map_revision += 1;
+}
+
+// Check if a tile is on the outer boundary of the map, which can never be dug
+fn is_boundary(x: i32, y: i32, map: &Map) -> bool {
+    let (map_width, map_height) = map_size(map);
+    x <= 0 || y <= 0 || x >= map_width - 1 || y >= map_height - 1
+}
+
+// Check if the player has an item of the given kind equipped
+fn is_wielding(kind: Item, inventory: &[Object]) -> bool {
+    inventory
+        .iter()
+        .any(|item| item.item == Some(kind) && item.equipment.map_or(false, |e| e.equipped))
+}
+
+// Turn a wall into floor, unless it's on the map's boundary. Returns true if dug out.
+fn dig(x: i32, y: i32, tcod: &mut Tcod, game: &mut Game) -> bool {
+    if is_boundary(x, y, &game.map) || !game.map[x as usize][y as usize].blocked {
+        return false;
+    }
+    set_tile(x, y, Tile::empty(), tcod, game);
+    true
+}
+
+// Spend a turn digging into a wall with a pickaxe
+fn dig_with_pickaxe(x: i32, y: i32, tcod: &mut Tcod, game: &mut Game, objects: &[Object]) {
+    if is_boundary(x, y, &game.map) {
+        game.messages.add("This rock is far too hard to dig through.", RED);
+        return;
+    }
+
+    // Keep digging the same tile, or start over on a new one
+    let turns = match game.digging {
+        Some(digging) if (digging.x, digging.y) == (x, y) => digging.turns + 1,
+        _ => 1,
+    };
+
+    if turns < DIG_TURNS {
+        game.digging = Some(Digging { x, y, turns });
+        game.messages.add("You dig into the wall.", LIGHT_GREY);
+    } else {
+        // Break through, and look through the new opening
+        game.digging = None;
+        dig(x, y, tcod, game);
+        let (player_x, player_y) = objects[PLAYER].pos();
+        tcod.fov.compute_fov(player_x, player_y, FOV_RADIUS, FOV_LIGHT_WALLS, FOV_ALGO);
+        game.messages.add("You break through the wall!", LIGHT_GREY);
+    }
+}
+
+// Price of an item in the town's shops (they buy items back for half of it)
+fn item_price(item: Item) -> i32 {
+    match item {
+        Item::Heal => 20,
+        Item::Lightning => 40,
+        Item::Confuse => 30,
+        Item::Fireball => 50,
+        Item::Sword => 60,
+        Item::Shield => 50,
+        Item::Pickaxe => 40,
+        Item::DigWand => 60,
+        Item::Torch => 10,
+        Item::Lantern => 80,
+        Item::Haste => 40,
+        Item::Slow => 30,
+        Item::WarAxe => 90,
+        Item::Summon => 70,
+        Item::Crown => 500,
+    }
+}
+
+// Open the interaction menu of a non-hostile character
+fn interact_with_npc(npc_id: usize, tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) {
+    match objects[npc_id].npc.clone() {
+        Some(Npc::Shopkeeper { stock }) => shop_menu(&objects[npc_id].name, &stock, tcod, game),
+        Some(Npc::Healer) => healer_menu(tcod, game, objects),
+        None => {}
+    }
+}
+
+// Buy items from, or sell items to, a shopkeeper
+fn shop_menu(name: &str, stock: &[Item], tcod: &mut Tcod, game: &mut Game) {
+    let header = format!("The {} greets you. You have {} gold.\n", name, game.gold);
+    match menu(&header, &["Buy", "Sell"], INVENTORY_WIDTH, &mut tcod.root) {
+        Some(0) => {
+            // Show the stock of the shop, with prices
+            let options: Vec<_> = stock
+                .iter()
+                .map(|&item| format!("{} ({} gold)", create_item(item, 0, 0).name, item_price(item)))
+                .collect();
+            let choice = menu(
+                "Press the key next to an item to buy it, or any other to cancel.\n",
+                &options,
+                INVENTORY_WIDTH,
+                &mut tcod.root,
+            );
+
+            if let Some(index) = choice {
+                let item = stock[index];
+                let price = item_price(item);
+                if game.gold < price {
+                    game.messages.add("You cannot afford that.", RED);
+                } else if game.inventory.len() >= 26 {
+                    game.messages.add("Your inventory is full.", RED);
+                } else {
+                    // Pay for the item and add it to the inventory
+                    let object = create_item(item, 0, 0);
+                    game.gold -= price;
+                    game.messages.add(
+                        format!("You bought a {} for {} gold.", object.name, price),
+                        GREEN,
+                    );
+                    game.inventory.push(object);
+                }
+            }
+        }
+        Some(1) => {
+            // Show the inventory; if an item is selected, sell it
+            let inventory_index = inventory_menu(
+                &game.inventory,
+                "Press the key next to an item to sell it for half its price, or any other to cancel.\n",
+                &mut tcod.root,
+            );
+            if let Some(inventory_index) = inventory_index {
+                let mut object = game.inventory.remove(inventory_index);
+
+                // Dequip the item if it is Equipment
+                if object.equipment.is_some() {
+                    object.dequip(&mut game.messages);
+                }
+
+                // Get paid for the item
+                let price = object.item.map_or(0, item_price) / 2;
+                game.gold += price;
+                game.messages.add(
+                    format!("You sold a {} for {} gold.", object.name, price),
+                    YELLOW,
+                );
+            }
+        }
+        _ => {}
+    }
+}
+
+// Pay the healer to restore the player's health
+fn healer_menu(tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) {
+    let header = format!(
+        "The healer can tend your wounds for {} gold. You have {} gold.\n",
+        HEALER_PRICE, game.gold
+    );
+    if menu(&header, &["Heal", "Leave"], INVENTORY_WIDTH, &mut tcod.root) != Some(0) {
+        return;
+    }
+
+    let max_hp = objects[PLAYER].max_hp(game);
+    if objects[PLAYER].fighter.map_or(0, |f| f.hp) >= max_hp {
+        game.messages.add("You are already at full health.", RED);
+    } else if game.gold < HEALER_PRICE {
+        game.messages.add("You cannot afford the healer's services.", RED);
+    } else {
+        // Pay the healer and heal up completely
+        game.gold -= HEALER_PRICE;
+        objects[PLAYER].heal(max_hp, game);
+        game.messages.add("The healer tends your wounds. You feel fully restored!", LIGHT_VIOLET);
+    }
+}
+
+// Find closest enemy, up to a maximum range, and in the player's FOV
+fn closest_monster(tcod: &Tcod, objects: &[Object], max_range: i32) -> Option<usize> {
+    let mut closest_monster = None;
+
+    // Start with (slightly more than) maximum range
+    let mut closest_dist = (max_range + 1) as f32; 
+
+    // Iterate through objects
+    for (id, object) in objects.iter().enumerate() {
+        // Check if this is a valid monster object
+        if (id != PLAYER)
+            && is_enemy(object, &objects[PLAYER])
+            && object.ai.is_some()
+            && tcod.lights.is_visible(object.x, object.y)
+        {
+            // Calculate distance between this object and the player
+            let dist = objects[PLAYER].distance_to(object);
+            if dist < closest_dist {
+                // It's closer, so remember it
+                closest_monster = Some(id);
+                closest_dist = dist;
+            }
+        }
+    }
+
+    // Return closest monster
+    closest_monster
+}
+
+// Confuse a monster (replacing its AI for a while) or the player
+fn confuse(object: &mut Object, num_turns: i32) {
+    if let Some(ai) = object.ai.take() {
+        // Replace the monster's AI with a "confused" one
+        // that will restore the old AI after some turns
+        let previous_ai = match ai {
+            AI::Confused { previous_ai, .. } => previous_ai,
+            ai => Box::new(ai),
+        };
+        object.ai = Some(AI::Confused {
+            previous_ai,
+            num_turns,
+        });
+    } else if let Some(fighter) = object.fighter.as_mut() {
+        // The player (or any fighter without AI) stumbles around
+        fighter.confused_turns = cmp::max(fighter.confused_turns, num_turns);
+    }
+}
+
+// Speed a creature up for a while, cancelling any slowness
+fn haste(object: &mut Object, num_turns: i32) {
+    if let Some(fighter) = object.fighter.as_mut() {
+        fighter.slowed_turns = 0;
+        fighter.hasted_turns = cmp::max(fighter.hasted_turns, num_turns);
+    }
+}
+
+// Slow a creature down for a while, cancelling any haste
+fn slow(object: &mut Object, num_turns: i32) {
+    if let Some(fighter) = object.fighter.as_mut() {
+        fighter.hasted_turns = 0;
+        fighter.slowed_turns = cmp::max(fighter.slowed_turns, num_turns);
+    }
+}
+
+// Find the free tile nearest to a position, looking up to a few steps away
+fn free_tile_near(x: i32, y: i32, map: &Map, objects: &[Object]) -> Option<(i32, i32)> {
+    let (map_width, map_height) = map_size(map);
+    (1..=3)
+        .flat_map(|r| (-r..=r).flat_map(move |dx| (-r..=r).map(move |dy| (x + dx, y + dy))))
+        .find(|&(x, y)| x >= 0 && y >= 0 && x < map_width && y < map_height && !is_blocked(x, y, map, objects))
+}
+
+// Use up some of a creature's energy, delaying its next action
+fn spend_energy(object: &mut Object, cost: i32) {
+    if let Some(fighter) = object.fighter.as_mut() {
+        fighter.energy -= cost;
+    }
+}
+
+// Return the hazard of the given kind at the given position, if any
+fn hazard_at(kind: HazardKind, x: i32, y: i32, hazards: &[Hazard]) -> Option<usize> {
+    hazards
+        .iter()
+        .position(|hazard| hazard.kind == kind && (hazard.x, hazard.y) == (x, y))
+}
+
+// Add a hazard to the level, strengthening any hazard of the same kind already there
+fn add_hazard(kind: HazardKind, x: i32, y: i32, strength: i32, game: &mut Game) {
+    match hazard_at(kind, x, y, &game.hazards) {
+        Some(id) => {
+            let hazard = &mut game.hazards[id];
+            hazard.strength = cmp::max(hazard.strength, strength);
+        }
+        None => game.hazards.push(Hazard { kind, x, y, strength }),
+    }
+}
+
+// Set a tile on fire, burning longer if there is something there to burn
+fn ignite(x: i32, y: i32, game: &mut Game) {
+    let tile = &mut game.map[x as usize][y as usize];
+    if tile.blocked {
+        return;
+    }
+    let strength = if tile.flammable {
+        // The debris burns away
+        tile.flammable = false;
+        FIRE_DURATION
+    } else {
+        FIRE_BARE_DURATION
+    };
+    add_hazard(HazardKind::Fire, x, y, strength, game);
+}
+
+// Release a gas cloud around the given position
+fn spawn_gas_cloud(kind: HazardKind, x: i32, y: i32, game: &mut Game) {
+    for dx in -1..2 {
+        for dy in -1..2 {
+            if !game.map[(x + dx) as usize][(y + dy) as usize].blocked {
+                // Thicker in the middle of the cloud
+                let strength = if (dx, dy) == (0, 0) {
+                    GAS_CLOUD_STRENGTH
+                } else {
+                    GAS_CLOUD_STRENGTH / 2
+                };
+                add_hazard(kind, x + dx, y + dy, strength, game);
+            }
+        }
+    }
+}
+
+// Let fire and gas affect whoever stands in them, then spread, drift and fade
+fn update_hazards(tcod: &Tcod, game: &mut Game, objects: &mut [Object]) {
+    // Affect every fighter standing in a hazard
+    let mut xp_to_gain = 0;
+    for hazard in game.hazards.clone() {
+        for (id, object) in objects.iter_mut().enumerate() {
+            if object.pos() != (hazard.x, hazard.y) || object.fighter.is_none() {
+                continue;
+            }
+            let (damage, verb) = match hazard.kind {
+                HazardKind::Fire => (FIRE_DAMAGE, "burned"),
+                HazardKind::PoisonGas => (POISON_DAMAGE, "poisoned"),
+                HazardKind::ConfusionGas => {
+                    confuse(object, GAS_CONFUSE_TURNS);
+                    continue;
+                }
+            };
+            if tcod.lights.is_visible(hazard.x, hazard.y) {
+                game.messages.add(
+                    format!("The {} is {} for {} hit points.", object.name, verb, damage),
+                    ORANGE,
+                );
+            }
+            if let Some(xp) = object.take_damage(damage, game) {
+                // Don't reward the player for their own demise!
+                if id != PLAYER {
+                    xp_to_gain += xp;
+                }
+            }
+        }
+    }
+    objects[PLAYER].fighter.as_mut().unwrap().xp += xp_to_gain;
+
+    // Spread, drift and fade
+    let (map_width, map_height) = map_size(&game.map);
+    for hazard in mem::take(&mut game.hazards) {
+        let strength = hazard.strength - 1;
+        match hazard.kind {
+            HazardKind::Fire => {
+                // Fire spreads over flammable neighbouring tiles
+                for &(dx, dy) in &[(-1, 0), (1, 0), (0, -1), (0, 1)] {
+                    let (x, y) = (hazard.x + dx, hazard.y + dy);
+                    if x < 0 || y < 0 || x >= map_width || y >= map_height {
+                        continue;
+                    }
+                    if game.map[x as usize][y as usize].flammable
+                        && rand::thread_rng().gen_range(0, 100) < FIRE_SPREAD_CHANCE
+                    {
+                        ignite(x, y, game);
+                    }
+                }
+                if strength > 0 {
+                    add_hazard(hazard.kind, hazard.x, hazard.y, strength, game);
+                }
+            }
+            HazardKind::PoisonGas | HazardKind::ConfusionGas => {
+                // Gas drifts in a random direction, if there is room for it
+                let dx = rand::thread_rng().gen_range(-1, 2);
+                let dy = rand::thread_rng().gen_range(-1, 2);
+                let (x, y) = if game.map[(hazard.x + dx) as usize][(hazard.y + dy) as usize].blocked {
+                    (hazard.x, hazard.y)
+                } else {
+                    (hazard.x + dx, hazard.y + dy)
+                };
+
+                if strength >= GAS_SPREAD_STRENGTH && (x, y) != (hazard.x, hazard.y) {
+                    // Thick gas spreads out, leaving some of itself behind
+                    add_hazard(hazard.kind, hazard.x, hazard.y, strength / 2, game);
+                    add_hazard(hazard.kind, x, y, strength / 2, game);
+                } else if strength > 0 {
+                    add_hazard(hazard.kind, x, y, strength, game);
+                }
+            }
+        }
+    }
+}
+
+// Return the trap object at the given position, if any
+fn trap_at(x: i32, y: i32, objects: &[Object]) -> Option<usize> {
+    objects
+        .iter()
+        .position(|object| object.trap.is_some() && object.pos() == (x, y))
+}
+
+// Spring the trap the player just stepped on
+fn trigger_trap(trap_id: usize, tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) {
+    // The trap is revealed, whatever it does
+    let kind = match objects[trap_id].trap.as_mut() {
+        Some(trap) => {
+            trap.revealed = true;
+            trap.kind
+        }
+        None => return,
+    };
+    objects[trap_id].always_visible = true;
+    let (trap_x, trap_y) = objects[trap_id].pos();
+
+    match kind {
+        TrapKind::Dart => {
+            game.messages.add(
+                format!(
+                    "A dart shoots out of the floor and hits you for {} hit points!",
+                    DART_DAMAGE
+                ),
+                RED,
+            );
+            objects[PLAYER].take_damage(DART_DAMAGE, game);
+        }
+        TrapKind::Teleport => {
+            game.messages.add("The floor glows, and you are teleported away!", LIGHT_VIOLET);
+            let (x, y) = random_free_position(&game.map, objects);
+            objects[PLAYER].set_pos(x, y);
+        }
+        TrapKind::ConfusionGas => {
+            game.messages.add("A cloud of violet gas bursts from the floor!", LIGHT_VIOLET);
+            spawn_gas_cloud(HazardKind::ConfusionGas, trap_x, trap_y, game);
+        }
+        TrapKind::PoisonGas => {
+            game.messages.add("A cloud of green gas bursts from the floor!", LIGHT_GREEN);
+            spawn_gas_cloud(HazardKind::PoisonGas, trap_x, trap_y, game);
+        }
+        TrapKind::Alarm => {
+            game.messages.add("A shrill alarm rings out!", YELLOW);
+
+            // Nearby monsters come running towards the alarm
+            for id in 0..objects.len() {
+                let hostile = objects[id].ai.is_some() && is_enemy(&objects[id], &objects[PLAYER]);
+                if hostile && objects[id].distance(trap_x, trap_y) <= ALARM_RADIUS as f32 {
+                    if let Some(fighter) = objects[id].fighter.as_mut() {
+                        fighter.alertness = Alertness::Alert;
+                    }
+                    if let Some(AI::Confused { .. }) = objects[id].ai {
+                        // Too confused to care
+                    } else {
+                        objects[id].ai = Some(AI::Hunt { x: trap_x, y: trap_y });
+                    }
+                    for _ in 0..ALARM_STEPS {
+                        move_towards(id, trap_x, trap_y, &game.map, objects);
+                    }
+                }
+            }
+        }
+        TrapKind::Trapdoor => {
+            game.messages.add("The floor gives way! You fall through a trapdoor!", ORANGE);
+
+            // Land somewhere on the level below
+            let level = game.dungeon_level + 1;
+            change_level(tcod, game, objects, level);
+            let (x, y) = random_free_position(&game.map, objects);
+            objects[PLAYER].set_pos(x, y);
+            game.messages.add(game.theme.data().feeling, LIGHT_GREY);
+        }
+    }
+}
+
+// Find a random passable position with nothing on it
+fn random_free_position(map: &Map, objects: &[Object]) -> (i32, i32) {
+    let (map_width, map_height) = map_size(map);
+    loop {
+        let x = rand::thread_rng().gen_range(0, map_width);
+        let y = rand::thread_rng().gen_range(0, map_height);
+        if !is_blocked(x, y, map, objects) && !objects.iter().any(|object| object.pos() == (x, y)) {
+            return (x, y);
+        }
+    }
+}
+
+// Spend a turn searching the tiles around the player for secret doors and traps.
+// The chance to find each one grows with the player's level.
+fn search(tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) {
+    let (player_x, player_y) = objects[PLAYER].pos();
+    let (map_width, map_height) = map_size(&game.map);
+    let chance = cmp::min(
+        SEARCH_BASE_CHANCE + objects[PLAYER].level * SEARCH_LEVEL_BONUS,
+        SEARCH_MAX_CHANCE,
+    );
+
+    let mut found = false;
+    for x in (player_x - SEARCH_RADIUS)..(player_x + SEARCH_RADIUS + 1) {
+        for y in (player_y - SEARCH_RADIUS)..(player_y + SEARCH_RADIUS + 1) {
+            if x < 0 || y < 0 || x >= map_width || y >= map_height {
+                continue;
+            }
+            if game.map[x as usize][y as usize].hidden && rand::thread_rng().gen_range(0, 100) < chance {
+                // Found it! Open up the secret door
+                set_tile(x, y, Tile::door(), tcod, game);
+                game.messages.add("You find a secret door!", LIGHT_CYAN);
+                found = true;
+            }
+        }
+    }
+
+    // Look for hidden traps nearby
+    for object in objects.iter_mut() {
+        let hidden_trap = object.trap.map_or(false, |trap| !trap.revealed);
+        let nearby = (object.x - player_x).abs() <= SEARCH_RADIUS && (object.y - player_y).abs() <= SEARCH_RADIUS;
+        if hidden_trap && nearby && rand::thread_rng().gen_range(0, 100) < chance {
+            // Found it! Reveal the trap
+            if let Some(trap) = object.trap.as_mut() {
+                trap.revealed = true;
+            }
+            object.always_visible = true;
+            game.messages.add(format!("You find a {}!", object.name), LIGHT_CYAN);
+            found = true;
+        }
+    }
+
+    if found {
+        // Look through the newly found doors
+        tcod.fov.compute_fov(player_x, player_y, FOV_RADIUS, FOV_LIGHT_WALLS, FOV_ALGO);
+    } else {
+        game.messages.add("You search the area, but find nothing.", WHITE);
+    }
+}
+
+// Add to the player's inventory and remove from the map
+fn pick_item_up(object_id: usize, game: &mut Game, objects: &mut Vec<Object>) {
+    // Check if inventory is full
+    if game.inventory.len() >= 26 {
+        game.messages.add(
+            format!(
+                "Your inventory is full, cannot pick up {}.",
+                objects[object_id].name
+            ),
+            RED,
+        );
+    } else {
+        // Add item to inventory
+        let item = objects.swap_remove(object_id);
+        game.messages.add(
+            format!("You picked up a {}!", item.name),
+            GREEN
+        );
+
+        // Get index, slot (if exists) of new item
+        let index = game.inventory.len();
+        let slot = item.equipment.map(|e| e.slot);
+
+        // Add item to inventory
+        game.inventory.push(item);
+
+        // Automatically equip, if the corresponding equipment slot is unused
+        if let Some(slot) = slot {
+            if get_equipped_in_slot(slot, &game.inventory).is_none() {
+                game.inventory[index].equip(&mut game.messages);
+            }
+        }
+    }
+}
+
+// Drop an item
+fn drop_item(inventory_id: usize, game: &mut Game, objects: &mut Vec<Object>) {
+    // Remove the item from the inventory
+    let mut item = game.inventory.remove(inventory_id);
+
+    // Dequip the item if it is Equipment
+    if item.equipment.is_some() {
+        item.dequip(&mut game.messages);
+    }
+
+    // Set the screen position for where to draw the item
+    item.set_pos(objects[PLAYER].x, objects[PLAYER].y);
+
+    // Send a message about the activity
+    game.messages.add(format!("You dropped a {}.", item.name), YELLOW);
+    
+    // Push the item to the objects array
+    objects.push(item);
+}
+
+fn ai_take_turn(monster_id: usize, tcod: &Tcod, game: &mut Game, objects: &mut [Object]) {
+    // Take AI component of monster
+    if let Some(ai) = objects[monster_id].ai.take() {
+        // Without its leader, a pack falls apart
+        let ai = if leader_fallen(monster_id, objects) {
+            scatter(monster_id, tcod, game, objects, ai)
+        } else {
+            ai
+        };
+
+        // Perform action based on AI variant (return new AI), unless there's an enemy
+        // other than the player to deal with first
+        let new_ai = match monster_target(monster_id, &ai, game, objects) {
+            Some(target_id) => {
+                fight_monster(monster_id, target_id, tcod, game, objects);
+                ai
+            }
+            None => match ai {
+                AI::Basic => ai_basic(monster_id, tcod, game, objects),
+                AI::Wander => ai_wander(monster_id, tcod, game, objects),
+                AI::Hunt { x, y } => ai_hunt(monster_id, tcod, game, objects, x, y),
+                AI::Search { x, y, turns } => ai_search(monster_id, tcod, game, objects, x, y, turns),
+                AI::Flee { turns } => ai_flee(monster_id, tcod, game, objects, turns),
+                AI::Return => ai_return(monster_id, tcod, game, objects),
+                AI::Ally => ai_ally(monster_id, tcod, game, objects),
+                AI::Confused {
+                    previous_ai,
+                    num_turns,
+                } => ai_confused(monster_id, tcod, game, objects, previous_ai, num_turns),
+            },
+        };
+
+        // Set new AI of monster
+        objects[monster_id].ai = Some(new_ai);
+    }
+}
 
-                    // Return the troll
-                    object
-                }
-                _ => unreachable!(),
-            };
+fn ai_basic(monster_id: usize, tcod: &Tcod, game: &mut Game, objects: &mut [Object]) -> AI {
+    // A monster that knows about the player fights them while it can see them
+    let (monster_x, monster_y) = objects[monster_id].pos();
+    let fighter = objects[monster_id].fighter.unwrap();
+
+    // Lost sight of the player, go after them
+    if !player_in_sight(monster_id, &game.map, objects) {
+        let (x, y) = fighter.last_seen.unwrap_or((monster_x, monster_y));
+        return ai_hunt(monster_id, tcod, game, objects, x, y);
+    }
+    let player_pos = objects[PLAYER].pos();
+    if let Some(fighter) = objects[monster_id].fighter.as_mut() {
+        fighter.last_seen = Some(player_pos);
+    }
 
-            // Give the monster life!
-            monster.alive = true;
+    // Let the rest of the pack know where the player is
+    alert_pack(monster_id, objects);
 
-            // Add monster to objects list
-            objects.push(monster);
+    // Badly hurt monsters run away, unless their leader is there to keep them fighting
+    let wounded = fighter.hp * 100 < fighter.flee_below * objects[monster_id].max_hp(game);
+    if wounded && !leader_nearby(monster_id, objects) {
+        if tcod.lights.is_visible(monster_x, monster_y) {
+            game.messages.add(format!("The {} turns to flee!", objects[monster_id].name), ORANGE);
         }
+        return ai_flee(monster_id, tcod, game, objects, 0);
     }
 
-    // Define maximum number of items per room based on level
-    let max_items = from_dungeon_level(
-        &[
-            Transition { level: 1, value: 1 },
-            Transition { level: 4, value: 2 },
-        ],
-        level,
-    );
+    // Casters use their magic whenever they get the chance
+    if cast_spell(monster_id, tcod, game, objects) {
+        return AI::Basic;
+    }
 
-    // Choose random number of items
-    let num_items = rand::thread_rng().gen_range(0, max_items + 1);
+    // Ranged attackers keep their distance, and shoot when they have a clear line of fire
+    if let Some(ranged) = fighter.ranged.filter(|ranged| ranged.ammo != Some(0)) {
+        let (player_x, player_y) = objects[PLAYER].pos();
+        let distance = objects[monster_id].distance_to(&objects[PLAYER]);
+        let retreated = distance < ranged.preferred_distance as f32 && {
+            update_player_maps(game, objects);
+            let approach = &game.dijkstra.approach;
+            move_downhill(monster_id, &game.map, objects, |x, y| {
+                (approach.distance(x, y) - ranged.preferred_distance).abs()
+            })
+        };
+        if retreated {
+            return AI::Basic;
+        }
+        let in_range = distance <= ranged.range as f32;
+        if in_range && has_line_of_fire((monster_x, monster_y), (player_x, player_y), &game.map) {
+            shoot(monster_id, player_x, player_y, tcod, game, objects);
+            return AI::Basic;
+        }
+    }
 
-    for _ in 0..num_items {
-        // Choose random spot for this item
-        let x = rand::thread_rng().gen_range(room.x1 + 1, room.x2);
-        let y = rand::thread_rng().gen_range(room.y1 + 1, room.y2);
+    // Pack members gather before closing in, then spread out around the player
+    if let Some(pack) = fighter.pack {
+        if objects[monster_id].distance_to(&objects[PLAYER]) >= 2.0 {
+            pack_move(monster_id, pack, game, objects);
+            return AI::Basic;
+        }
+    }
 
-        // Only place it if the tile is not blocked
-        if !is_blocked(x, y, map, objects) {
-            // Create item generator table
-            let item_chances = &mut [
-                Weighted {
-                    weight: 35,
-                    item: Item::Heal,
-                },
-                Weighted {
-                    weight: from_dungeon_level(
-                        &[Transition { level: 4, value: 25 }],
-                        level,
-                    ),
-                    item: Item::Lightning,
-                },
-                Weighted {
-                    weight: from_dungeon_level(
-                        &[Transition { level: 6, value: 25 }],
-                        level,
-                    ),
-                    item: Item::Fireball,
-                },
-                Weighted {
-                    weight: from_dungeon_level(
-                        &[Transition { level: 2, value: 10 }],
-                        level,
-                    ),
-                    item: Item::Confuse,
-                },
-                Weighted {
-                    weight: from_dungeon_level(
-                        &[Transition { level: 4, value: 5 }],
-                        level
-                    ),
-                    item: Item::Sword, 
-                },
-                Weighted {
-                    weight: from_dungeon_level(
-                        &[Transition { level: 8, value: 15 }],
-                        level,
-                    ),
-                    item: Item::Shield, 
-                },
-            ];
-
-            // Create item choice generator
-            let item_choice = WeightedChoice::new(item_chances);
-
-            // Generate the item
-            let mut item = match item_choice.sample(&mut rand::thread_rng()) {
-                Item::Heal => {
-                    // Create a healing potion
-                    let mut object = Object::new(x, y, '!', "healing potion", VIOLET, false);
-                    object.item = Some(Item::Heal);
-
-                    // Return the item
-                    object
-                }
-                Item::Lightning => {
-                    // Create a lightning bolt scroll
-                    let mut object = Object::new(x, y, '#', "scroll of lightning bolt", LIGHT_YELLOW, false);
-                    object.item = Some(Item::Lightning);
+    // Some monsters hang back until the player has been worn down
+    let player_healthy = objects[PLAYER].fighter.map_or(false, |f| f.hp * 2 > objects[PLAYER].max_hp(game));
+    let hanging_back = fighter.keep_distance > 0 && player_healthy;
+    let moved = if hanging_back {
+        update_player_maps(game, objects);
+        let approach = &game.dijkstra.approach;
+        let keep_distance = fighter.keep_distance;
+        move_downhill(monster_id, &game.map, objects, |x, y| {
+            (approach.distance(x, y) - keep_distance).abs()
+        })
+    } else {
+        false
+    };
 
-                    // Return the item
-                    object
-                }
-                Item::Confuse => {
-                    // Create a confuse scroll
-                    let mut object = Object::new(x, y, '#', "scroll of confusion", LIGHT_YELLOW, false);
-                    object.item = Some(Item::Confuse);
-                    
-                    // Return the object
-                    object
-                } 
-                Item::Fireball => {
-                    // Create a fireball scroll
-                    let mut object = Object::new(x, y, '#', "scroll of fireball", LIGHT_YELLOW, false);
-                    object.item = Some(Item::Fireball);
-                    
-                    // Return the object
-                    object
-                }
-                Item::Sword => {
-                    // Create a sword
-                    let mut object = Object::new(x, y, '/', "sword", SKY, false);
-                    object.item = Some(Item::Sword);
-                    object.equipment = Some(Equipment {
-                        equipped: false,
-                        slot: Slot::RightHand,
-                        max_hp_bonus: 0,
-                        defense_bonus: 0,
-                        power_bonus: 3,
-                    });
+    if moved {
+        // Back to a safe distance
+    } else if objects[monster_id].distance_to(&objects[PLAYER]) >= 2.0 {
+        // Move towards player if far away, finding a way around obstacles
+        if !hanging_back {
+            let (player_x, player_y) = objects[PLAYER].pos();
+            move_astar(monster_id, player_x, player_y, &game.map, objects);
+        }
+    } else if objects[PLAYER].fighter.map_or(false, |f| f.hp > 0) {
+        // Close enough, attack! (if the player is still alive.)
+        melee(monster_id, PLAYER, tcod, game, objects);
+    }
 
-                    // Return the object
-                    object
-                }
+    // Return Basic AI variant
+    AI::Basic
+}
 
-                Item::Shield => {
-                    // Create a shield
-                    let mut object = Object::new(x, y, '[', "shield", DARKER_ORANGE, false);
-                    object.item = Some(Item::Shield);
-                    object.equipment = Some(Equipment {
-                        equipped: false,
-                        slot: Slot::LeftHand,
-                        max_hp_bonus: 0,
-                        defense_bonus: 1,
-                        power_bonus: 0,
-                    });
+/// Let a monster caster try one of its spells: healing itself or the most wounded ally in
+/// reach, or confusing or zapping the player. Returns true if a spell was cast, which takes
+/// up the caster's turn.
+fn cast_spell(caster_id: usize, tcod: &Tcod, game: &mut Game, objects: &mut [Object]) -> bool {
+    if objects[caster_id].spells.is_empty() || rand::thread_rng().gen_range(0, 100) >= SPELL_CHANCE {
+        return false;
+    }
+    let caster_pos = objects[caster_id].pos();
+    let faction = objects[caster_id].fighter.map(|f| f.faction);
+    let player_distance = objects[caster_id].distance_to(&objects[PLAYER]);
+    let player_confused = objects[PLAYER].fighter.map_or(false, |f| f.confused_turns > 0);
 
-                    // Return the object
-                    object
-                }
-            };
-            
-            // Set item to be always visible once found
-            item.always_visible = true;
+    // Allies below half health that the caster can see
+    let wounded_ally = objects
+        .iter()
+        .enumerate()
+        .filter(|&(id, object)| {
+            id != PLAYER
+                && object.ai.is_some()
+                && object.fighter.map(|f| f.faction) == faction
+                && object.fighter.map_or(false, |f| f.hp * 2 < object.max_hp(game))
+                && object.distance_to(&objects[caster_id]) <= HEAL_RANGE as f32
+                && has_line_of_fire(caster_pos, object.pos(), &game.map)
+        })
+        .min_by_key(|&(_, object)| object.fighter.map_or(0, |f| f.hp))
+        .map(|(id, _)| id);
 
-            // Add item to objects list
-            objects.push(item);
+    // Pick one of the spells that would do some good right now
+    let castable: Vec<Spell> = objects[caster_id]
+        .spells
+        .iter()
+        .cloned()
+        .filter(|spell| match spell {
+            Spell::Heal => wounded_ally.is_some(),
+            Spell::Lightning => player_distance <= LIGHTNING_RANGE as f32,
+            Spell::Confuse => !player_confused && player_distance <= CONFUSE_RANGE as f32,
+        })
+        .collect();
+    match rand::thread_rng().choose(&castable) {
+        Some(Spell::Heal) => {
+            heal_effect(caster_id, wounded_ally.unwrap(), tcod, game, objects);
         }
+        Some(Spell::Lightning) => lightning_effect(caster_id, PLAYER, tcod, game, objects),
+        Some(Spell::Confuse) => confuse_effect(caster_id, PLAYER, tcod, game, objects),
+        None => return false,
     }
+    true
 }
 
-fn make_map(objects: &mut Vec<Object>, level: u32) -> Map {
-    // Fill map with "blocked" tiles
-    let mut map = vec![vec![Tile::wall(); MAP_HEIGHT as usize]; MAP_WIDTH as usize];
-
-    // Player is the first element, remove everything else.
-    // NOTE: works only when the player is the first object!
-    assert_eq!(&objects[PLAYER] as *const _, &objects[0] as *const _);
-    objects.truncate(1);
-
-    // Create rooms vector
-    let mut rooms = vec![];
+/// Move a pack member into position: hang back until enough of the pack has gathered, then
+/// head for a free spot next to the player, so the pack surrounds them instead of queueing
+/// up behind each other in a corridor.
+fn pack_move(monster_id: usize, pack: Pack, game: &mut Game, objects: &mut [Object]) {
+    if !pack_assembled(monster_id, pack, objects) {
+        update_player_maps(game, objects);
+        let approach = &game.dijkstra.approach;
+        move_downhill(monster_id, &game.map, objects, |x, y| {
+            (approach.distance(x, y) - PACK_HOLD_DISTANCE).abs()
+        });
+        return;
+    }
 
-    // Generate rooms
-    for _ in 0..MAX_ROOMS {
-        // Generate random width and height for new room
-        let w = rand::thread_rng().gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
-        let h = rand::thread_rng().gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
-        
-        // Generate random position without going out of the boundaries of the map
-        let x = rand::thread_rng().gen_range(0, MAP_WIDTH - w);
-        let y = rand::thread_rng().gen_range(0, MAP_HEIGHT - h);
+    // The nearest free spot around the player. With none left, wait for an opening.
+    let (player_x, player_y) = objects[PLAYER].pos();
+    let (monster_x, monster_y) = objects[monster_id].pos();
+    let target = (-1..=1)
+        .flat_map(|dx| (-1..=1).map(move |dy| (player_x + dx, player_y + dy)))
+        .filter(|&(x, y)| !is_blocked(x, y, &game.map, objects))
+        .min_by_key(|&(x, y)| (x - monster_x).pow(2) + (y - monster_y).pow(2));
+    if let Some((x, y)) = target {
+        move_astar(monster_id, x, y, &game.map, objects);
+    }
+}
 
-        // Create new room
-        let new_room = Rect::new(x, y, w, h);
+// Check if enough of a pack has gathered around one of its members to attack together
+fn pack_assembled(monster_id: usize, pack: Pack, objects: &[Object]) -> bool {
+    let members: Vec<&Object> = objects
+        .iter()
+        .filter(|object| object.fighter.and_then(|f| f.pack).map_or(false, |p| p.id == pack.id))
+        .collect();
+    let nearby = members
+        .iter()
+        .filter(|member| member.distance_to(&objects[monster_id]) <= PACK_RADIUS as f32)
+        .count();
+    nearby >= cmp::min(members.len(), PACK_ASSEMBLED)
+}
 
-        // Run through the other rooms and see if they intersect with this one
-        let failed = rooms.iter().any(|other_room| new_room.intersects_with(other_room));
+// Tell the rest of a monster's pack where the player is, waking them up and drawing them in
+fn alert_pack(monster_id: usize, objects: &mut [Object]) {
+    let pack_id = match objects[monster_id].fighter.and_then(|f| f.pack) {
+        Some(pack) => pack.id,
+        None => return,
+    };
+    let (player_x, player_y) = objects[PLAYER].pos();
+    for (id, object) in objects.iter_mut().enumerate() {
+        if id == monster_id || object.fighter.and_then(|f| f.pack).map_or(true, |p| p.id != pack_id) {
+            continue;
+        }
+        if let Some(fighter) = object.fighter.as_mut() {
+            fighter.alertness = Alertness::Alert;
+        }
+        match object.ai {
+            Some(AI::Wander) | Some(AI::Search { .. }) | Some(AI::Return) | Some(AI::Hunt { .. }) => {
+                object.ai = Some(AI::Hunt {
+                    x: player_x,
+                    y: player_y,
+                });
+            }
+            _ => {}
+        }
+    }
+}
 
-        if !failed {
-            // This means there are no intersections, so this room is valid
-            // "carve" it to the map's wall tiles
-            create_room(new_room, &mut map);
+// Find the living leader of a monster's pack, if the monster is a follower
+fn pack_leader(monster_id: usize, objects: &[Object]) -> Option<usize> {
+    let pack = objects[monster_id].fighter.and_then(|f| f.pack).filter(|pack| !pack.leader)?;
+    let leader = Some(Pack {
+        id: pack.id,
+        leader: true,
+    });
+    objects.iter().position(|object| object.fighter.and_then(|f| f.pack) == leader)
+}
 
-            // Add some content to this room, such as monsters
-            place_objects(new_room, &map, objects, level);
+// Check if a follower's leader is close enough to keep up its morale
+fn leader_nearby(monster_id: usize, objects: &[Object]) -> bool {
+    pack_leader(monster_id, objects)
+        .map_or(false, |leader_id| objects[leader_id].distance_to(&objects[monster_id]) <= PACK_RADIUS as f32)
+}
 
-            // Center coordinates of the new room, will be useful later
-            let (new_x, new_y) = new_room.center();
+// Check if a monster follows a leader that is no longer alive
+fn leader_fallen(monster_id: usize, objects: &[Object]) -> bool {
+    let follower = objects[monster_id].fighter.and_then(|f| f.pack).map_or(false, |pack| !pack.leader);
+    follower && pack_leader(monster_id, objects).is_none()
+}
 
-            if rooms.is_empty() {
-                // This is the first room, where the player starts at
-                objects[PLAYER].set_pos(new_x, new_y);
-            }  else {
-                // All rooms after the first:
-                // connect it to the previous room with a tunnel
-                // center coordinates of the previous room
-                let (prev_x, prev_y) = rooms[rooms.len() - 1].center();
-            
-                // Toss a coin (random bool value -- either true or false)
-                if rand::random() {
-                    // First move horizontally, then vertically
-                    create_h_tunnel(prev_x, new_x, prev_y, &mut map);
-                    create_v_tunnel(prev_y, new_y, new_x, &mut map);
-                } else {
-                    // First move vertically, then horizontally
-                    create_v_tunnel(prev_y, new_y, prev_x, &mut map);
-                    create_h_tunnel(prev_x, new_x, new_y, &mut map);
-                }
+// Leave a pack whose leader has died. Followers in the middle of a fight run for it.
+fn scatter(monster_id: usize, tcod: &Tcod, game: &mut Game, objects: &mut [Object], ai: AI) -> AI {
+    let (monster_x, monster_y) = objects[monster_id].pos();
+    let fighter = match objects[monster_id].fighter.as_mut() {
+        Some(fighter) => fighter,
+        None => return ai,
+    };
+    fighter.pack = None;
+    match ai {
+        AI::Basic | AI::Hunt { .. } | AI::Search { .. } if fighter.alertness == Alertness::Alert => {
+            if tcod.lights.is_visible(monster_x, monster_y) {
+                game.messages.add(
+                    format!("With its leader dead, the {} loses heart!", objects[monster_id].name),
+                    ORANGE,
+                );
             }
+            AI::Flee { turns: 0 }
+        }
+        ai => ai,
+    }
+}
 
-            // Finally, append the new room to the list
-            rooms.push(new_room);
+/// Allies stick close to the player, and go after hostile monsters that come near them
+fn ai_ally(ally_id: usize, tcod: &Tcod, game: &mut Game, objects: &mut [Object]) -> AI {
+    let enemy_id = nearest_enemy(ally_id, &game.map, objects)
+        .filter(|&id| objects[id].distance_to(&objects[PLAYER]) <= ALLY_LEASH as f32);
+    match enemy_id {
+        Some(enemy_id) => fight_monster(ally_id, enemy_id, tcod, game, objects),
+        None if objects[ally_id].distance_to(&objects[PLAYER]) > ALLY_FOLLOW_DISTANCE as f32 => {
+            let (player_x, player_y) = objects[PLAYER].pos();
+            move_astar(ally_id, player_x, player_y, &game.map, objects);
         }
+        None => {}
     }
+    AI::Ally
+}
 
-    // Create stairs at the center of the last room
-    let (last_room_x, last_room_y) = rooms[rooms.len() - 1].center();
-    let mut stairs = Object::new(last_room_x, last_room_y, '<', "stairs", WHITE, false);
-    stairs.always_visible = true;
-    objects.push(stairs);
+// Check if two fighters are on hostile sides
+fn is_enemy(a: &Object, b: &Object) -> bool {
+    match (a.fighter, b.fighter) {
+        (Some(a), Some(b)) => a.faction.is_hostile_to(b.faction),
+        _ => false,
+    }
+}
 
-    // Return the map
-    map
+// Find the nearest creature other than the player that a monster can see, and is hostile to
+fn nearest_enemy(monster_id: usize, map: &Map, objects: &[Object]) -> Option<usize> {
+    let monster = &objects[monster_id];
+    let sight_radius = monster.fighter.map_or(0, |f| f.sight_radius);
+    objects
+        .iter()
+        .enumerate()
+        .filter(|&(id, object)| {
+            id != PLAYER
+                && object.ai.is_some()
+                && is_enemy(monster, object)
+                && monster.distance_to(object) <= sight_radius as f32
+                && has_line_of_fire(monster.pos(), object.pos(), map)
+        })
+        .min_by_key(|&(_, object)| (object.x - monster.x).pow(2) + (object.y - monster.y).pow(2))
+        .map(|(id, _)| id)
 }
 
-fn is_blocked(x: i32, y: i32, map: &Map, objects: &[Object]) -> bool {
-    // First test the map tile
-    if map[x as usize][y as usize].blocked {
-        return true;
+// Pick a creature other than the player for a monster to fight: the nearest enemy in sight,
+// unless the monster is asleep or busy, or knows about the player and the player is closer
+fn monster_target(monster_id: usize, ai: &AI, game: &Game, objects: &[Object]) -> Option<usize> {
+    let fighter = objects[monster_id].fighter?;
+    let busy = matches!(ai, AI::Confused { .. } | AI::Flee { .. } | AI::Ally);
+    if busy || fighter.alertness == Alertness::Asleep {
+        return None;
+    }
+    let target_id = nearest_enemy(monster_id, &game.map, objects)?;
+    let player_distance = objects[monster_id].distance_to(&objects[PLAYER]);
+    let player_first = fighter.alertness == Alertness::Alert
+        && player_in_sight(monster_id, &game.map, objects)
+        && player_distance <= objects[monster_id].distance_to(&objects[target_id]);
+    if player_first {
+        None
+    } else {
+        Some(target_id)
     }
-    // Now check for any blocking objects
-    objects.iter().any(|object| object.blocks && object.pos() == (x, y))
 }
 
-// Move object by the given amount, if the destination is not blocked
-fn move_by(id: usize, dx: i32, dy: i32, map: &Map, objects: &mut [Object]) {
-    let (x, y) = objects[id].pos();
-    if !is_blocked(x + dx, y + dy, map, objects) {
-        objects[id].set_pos(x + dx, y + dy);
+/// Go after another monster: hit it when next to it, shoot at it when there's a clear line
+/// of fire, and move in on it otherwise
+fn fight_monster(monster_id: usize, target_id: usize, tcod: &Tcod, game: &mut Game, objects: &mut [Object]) {
+    let (target_x, target_y) = objects[target_id].pos();
+    let distance = objects[monster_id].distance_to(&objects[target_id]);
+    let in_range = objects[monster_id]
+        .fighter
+        .and_then(|f| f.ranged)
+        .map_or(false, |ranged| ranged.ammo != Some(0) && distance <= ranged.range as f32);
+    if distance < 2.0 {
+        melee(monster_id, target_id, tcod, game, objects);
+    } else if in_range && has_line_of_fire(objects[monster_id].pos(), (target_x, target_y), &game.map) {
+        shoot(monster_id, target_x, target_y, tcod, game, objects);
+    } else {
+        move_astar(monster_id, target_x, target_y, &game.map, objects);
     }
 }
 
-fn move_towards(id: usize, target_x: i32, target_y: i32, map: &Map, objects: &mut [Object]) {
-    // Vector from this object to the target, and distance
-    let dx = target_x - objects[id].x;
-    let dy = target_y - objects[id].y;
-    let distance = ((dx.pow(2) + dy.pow(2)) as f32).sqrt();
-
-    // Normalize it to length 1 (preserving direction), then round it and
-    // convert to integer so the movement is restricted to the map grid
-    let dx = (dx as f32 / distance).round() as i32;
-    let dy = (dy as f32 / distance).round() as i32;
-
-    // Move object
-    move_by(id, dx, dy, map, objects);
+// Attack a creature next to the attacker, making a racket. Kills made by the player's allies
+// count for the player.
+fn melee(attacker_id: usize, target_id: usize, tcod: &Tcod, game: &mut Game, objects: &mut [Object]) {
+    let (x, y) = objects[attacker_id].pos();
+    let (attacker, target) = mut_two(attacker_id, target_id, objects);
+    attacker.attack(target, game);
+    make_noise(Noise::Combat, x, y, tcod, game, objects);
+
+    if attacker_id != PLAYER && objects[attacker_id].fighter.map_or(false, |f| f.faction == Faction::Player) {
+        let xp = objects[attacker_id].fighter.map_or(0, |f| f.xp);
+        if let Some(fighter) = objects[attacker_id].fighter.as_mut() {
+            fighter.xp = 0;
+        }
+        if let Some(fighter) = objects[PLAYER].fighter.as_mut() {
+            fighter.xp += xp;
+        }
+    }
 }
 
-fn player_move_or_attack(dx: i32, dy: i32, game: &mut Game, objects: &mut [Object]) {
-    // The coordinates the player is moving to/attacking
-    let x = objects[PLAYER].x + dx;
-    let y = objects[PLAYER].y + dy;
+// Check if nothing but open ground lies on the straight line between two positions
+fn has_line_of_fire(from: (i32, i32), to: (i32, i32), map: &Map) -> bool {
+    Line::new(from, to).all(|(x, y)| !map[x as usize][y as usize].blocked)
+}
 
-    // Try to find an attackable object there
-    let target_id = objects.iter().position(|object| object.fighter.is_some() && object.pos() == (x, y));
+/// Shoot at a position with a ranged attack, using up some ammo. The projectile flies in a
+/// straight line until it hits a wall, or a creature which takes the hit whether it was the
+/// target or just in the way.
+fn shoot(shooter_id: usize, target_x: i32, target_y: i32, tcod: &Tcod, game: &mut Game, objects: &mut [Object]) {
+    let ranged = match objects[shooter_id].fighter.and_then(|f| f.ranged) {
+        Some(ranged) => ranged,
+        None => return,
+    };
+    let (shooter_x, shooter_y) = objects[shooter_id].pos();
+    let projectile = ranged.projectile.name();
+
+    // Follow the projectile until it hits something
+    let mut path = vec![];
+    let mut hit_id = None;
+    for (x, y) in Line::new((shooter_x, shooter_y), (target_x, target_y)) {
+        if game.map[x as usize][y as usize].blocked {
+            break;
+        }
+        path.push((x, y));
+        hit_id = objects.iter().position(|object| object.fighter.is_some() && object.pos() == (x, y));
+        if hit_id.is_some() {
+            break;
+        }
+    }
+    let (end_x, end_y) = path.last().cloned().unwrap_or((shooter_x, shooter_y));
+    game.shots.push(Shot {
+        path,
+        color: ranged.projectile.color(),
+    });
 
-    // Attack if target found, move otherwise
-    match target_id {
-        Some(target_id) => {
-            // Attack the target
-            let (player, target) = mut_two(PLAYER, target_id, objects);
-            player.attack(target, game);
+    match hit_id {
+        Some(hit_id) => {
+            let damage = ranged.power - objects[hit_id].defense(game);
+            if damage > 0 {
+                game.messages.add(
+                    format!(
+                        "{}'s {} hits {} for {} hit points.",
+                        objects[shooter_id].name, projectile, objects[hit_id].name, damage
+                    ),
+                    WHITE,
+                );
+                if let Some(xp) = objects[hit_id].take_damage(damage, game) {
+                    objects[shooter_id].fighter.as_mut().unwrap().xp += xp;
+                }
+            } else {
+                game.messages.add(
+                    format!(
+                        "{}'s {} hits {} but it has no effect!",
+                        objects[shooter_id].name, projectile, objects[hit_id].name
+                    ),
+                    WHITE,
+                );
+            }
         }
         None => {
-            // Move the player
-            move_by(PLAYER, dx, dy, &game.map, objects);
+            if tcod.lights.is_visible(end_x, end_y) {
+                game.messages.add(format!("{}'s {} hits the wall.", objects[shooter_id].name, projectile), WHITE);
+            }
+        }
+    }
+
+    // Use up the ammo
+    let name = objects[shooter_id].name.clone();
+    if let Some(ammo) = objects[shooter_id]
+        .fighter
+        .as_mut()
+        .and_then(|f| f.ranged.as_mut())
+        .and_then(|ranged| ranged.ammo.as_mut())
+    {
+        *ammo -= 1;
+        if *ammo == 0 {
+            game.messages.add(format!("{} runs out of {}s.", name, projectile), LIGHT_GREY);
         }
     }
 }
 
-// Find closest enemy, up to a maximum range, and in the player's FOV
-fn closest_monster(tcod: &Tcod, objects: &[Object], max_range: i32) -> Option<usize> {
-    let mut closest_monster = None;
+fn ai_wander(monster_id: usize, tcod: &Tcod, game: &mut Game, objects: &mut [Object]) -> AI {
+    let fighter = objects[monster_id].fighter.unwrap();
 
-    // Start with (slightly more than) maximum range
-    let mut closest_dist = (max_range + 1) as f32; 
+    // Put on its guard without seeing the player, it goes looking where it last heard or saw them
+    if fighter.alertness == Alertness::Alert && !player_in_sight(monster_id, &game.map, objects) {
+        let (x, y) = fighter.last_seen.unwrap_or_else(|| objects[monster_id].pos());
+        return ai_hunt(monster_id, tcod, game, objects, x, y);
+    }
+    if spots_player(monster_id, tcod, game, objects) {
+        return ai_basic(monster_id, tcod, game, objects);
+    }
 
-    // Iterate through objects
-    for (id, object) in objects.iter().enumerate() {
-        // Check if this is a valid monster object
-        if (id != PLAYER)
-            && object.fighter.is_some()
-            && object.ai.is_some()
-            && tcod.fov.is_in_fov(object.x, object.y)
-        {
-            // Calculate distance between this object and the player
-            let dist = objects[PLAYER].distance_to(object);
-            if dist < closest_dist {
-                // It's closer, so remember it
-                closest_monster = Some(id);
-                closest_dist = dist;
-            }
-        }
+    // Sleep, stand guard, or roam about
+    let roaming = fighter.alertness == Alertness::Wandering && fighter.guard_post.is_none();
+    if roaming && rand::thread_rng().gen_range(0, 100) < WANDER_CHANCE {
+        wander(monster_id, &game.map, objects);
     }
+    AI::Wander
+}
 
-    // Return closest monster
-    closest_monster
+fn ai_hunt(monster_id: usize, tcod: &Tcod, game: &mut Game, objects: &mut [Object], x: i32, y: i32) -> AI {
+    // Drop the trail as soon as the player is spotted
+    if spots_player(monster_id, tcod, game, objects) {
+        return ai_basic(monster_id, tcod, game, objects);
+    }
+
+    // Head for where the player was last seen or heard, and look around once there (or when stuck)
+    let previous_position = objects[monster_id].pos();
+    if objects[monster_id].distance(x, y) < 2.0 {
+        return AI::Search { x, y, turns: SEARCH_TURNS };
+    }
+    move_astar(monster_id, x, y, &game.map, objects);
+    if objects[monster_id].pos() == previous_position {
+        return AI::Search { x, y, turns: SEARCH_TURNS };
+    }
+    AI::Hunt { x, y }
 }
 
-// Add to the player's inventory and remove from the map
-fn pick_item_up(object_id: usize, game: &mut Game, objects: &mut Vec<Object>) {
-    // Check if inventory is full
-    if game.inventory.len() >= 26 {
-        game.messages.add(
-            format!(
-                "Your inventory is full, cannot pick up {}.",
-                objects[object_id].name
-            ),
-            RED,
-        );
+fn ai_search(
+    monster_id: usize,
+    tcod: &Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+    x: i32,
+    y: i32,
+    turns: i32,
+) -> AI {
+    if spots_player(monster_id, tcod, game, objects) {
+        return ai_basic(monster_id, tcod, game, objects);
+    }
+
+    // Give up after a while, and go back to what it was doing
+    if turns <= 0 {
+        return calm_down(monster_id, objects);
+    }
+
+    // Poke around the spot where the trail went cold
+    if objects[monster_id].distance(x, y) > SEARCH_AREA as f32 {
+        move_towards(monster_id, x, y, &game.map, objects);
     } else {
-        // Add item to inventory
-        let item = objects.swap_remove(object_id);
-        game.messages.add(
-            format!("You picked up a {}!", item.name),
-            GREEN
-        );
+        wander(monster_id, &game.map, objects);
+    }
+    AI::Search { x, y, turns: turns - 1 }
+}
 
-        // Get index, slot (if exists) of new item
-        let index = game.inventory.len();
-        let slot = item.equipment.map(|e| e.slot);
+fn ai_flee(monster_id: usize, tcod: &Tcod, game: &mut Game, objects: &mut [Object], turns: i32) -> AI {
+    // Calm down once out of the player's sight for long enough
+    let in_sight = player_in_sight(monster_id, &game.map, objects);
+    if !in_sight && turns >= FLEE_TURNS {
+        return calm_down(monster_id, objects);
+    }
 
-        // Add item to inventory
-        game.inventory.push(item);
+    // Run away from the player, fighting back only when cornered
+    update_player_maps(game, objects);
+    let flee = &game.dijkstra.flee;
+    let moved = move_downhill(monster_id, &game.map, objects, |x, y| flee.distance(x, y));
+    let cornered = !moved && objects[monster_id].distance_to(&objects[PLAYER]) < 2.0;
+    if cornered && objects[PLAYER].fighter.map_or(false, |f| f.hp > 0) {
+        melee(monster_id, PLAYER, tcod, game, objects);
+    }
 
-        // Automatically equip, if the corresponding equipment slot is unused
-        if let Some(slot) = slot {
-            if get_equipped_in_slot(slot, &game.inventory).is_none() {
-                game.inventory[index].equip(&mut game.messages);
-            }
-        }
+    // Count the turns spent out of sight
+    AI::Flee {
+        turns: if in_sight { 0 } else { turns + 1 },
     }
 }
 
-// Drop an item
-fn drop_item(inventory_id: usize, game: &mut Game, objects: &mut Vec<Object>) {
-    // Remove the item from the inventory
-    let mut item = game.inventory.remove(inventory_id);
+fn ai_return(monster_id: usize, tcod: &Tcod, game: &mut Game, objects: &mut [Object]) -> AI {
+    if spots_player(monster_id, tcod, game, objects) {
+        return ai_basic(monster_id, tcod, game, objects);
+    }
 
-    // Dequip the item if it is Equipment
-    if item.equipment.is_some() {
-        item.dequip(&mut game.messages);
+    // Walk back to the guard post, and stand guard there again
+    match objects[monster_id].fighter.and_then(|f| f.guard_post) {
+        Some((x, y)) if objects[monster_id].pos() != (x, y) => {
+            move_astar(monster_id, x, y, &game.map, objects);
+            AI::Return
+        }
+        _ => AI::Wander,
     }
+}
 
-    // Set the screen position for where to draw the item
-    item.set_pos(objects[PLAYER].x, objects[PLAYER].y);
+// Forget about the player, going back to the guard post if the monster has one, or wandering about otherwise
+fn calm_down(monster_id: usize, objects: &mut [Object]) -> AI {
+    if let Some(fighter) = objects[monster_id].fighter.as_mut() {
+        fighter.alertness = Alertness::Wandering;
+        if fighter.guard_post.is_some() {
+            return AI::Return;
+        }
+    }
+    AI::Wander
+}
 
-    // Send a message about the activity
-    game.messages.add(format!("You dropped a {}.", item.name), YELLOW);
-    
-    // Push the item to the objects array
-    objects.push(item);
+// Check if a monster sees the player and knows they're there, giving it a chance to notice them if not
+fn spots_player(monster_id: usize, tcod: &Tcod, game: &mut Game, objects: &mut [Object]) -> bool {
+    let alert = objects[monster_id].fighter.map_or(false, |f| f.alertness == Alertness::Alert);
+    player_in_sight(monster_id, &game.map, objects) && (alert || notice_player(monster_id, tcod, game, objects))
 }
 
-fn ai_take_turn(monster_id: usize, tcod: &Tcod, game: &mut Game, objects: &mut [Object]) {
-    // Take AI component of monster
-    if let Some(ai) = objects[monster_id].ai.take() {
-        // Perform action based on AI variant (return new AI)
-        let new_ai = match ai {
-            AI::Basic => ai_basic(monster_id, tcod, game, objects),
-            AI::Confused {
-                previous_ai,
-                num_turns,
-            } => ai_confused(monster_id, tcod, game, objects, previous_ai, num_turns),
-        };
+// Check if a monster can see the player: in line of sight and close enough, even in the dark
+fn player_in_sight(monster_id: usize, map: &Map, objects: &[Object]) -> bool {
+    let sight_radius = objects[monster_id].fighter.map_or(0, |f| f.sight_radius);
+    objects[monster_id].distance_to(&objects[PLAYER]) <= sight_radius as f32
+        && has_line_of_sight(objects[monster_id].pos(), objects[PLAYER].pos(), map)
+}
 
-        // Set new AI of monster
-        objects[monster_id].ai = Some(new_ai);
+// Give a monster that can see the player a chance to notice them, waking it up if need be.
+// Returns true if the player was noticed.
+fn notice_player(monster_id: usize, tcod: &Tcod, game: &mut Game, objects: &mut [Object]) -> bool {
+    if !notices_player(monster_id, game, objects) {
+        return false;
     }
-}
 
-fn ai_basic(monster_id: usize, tcod: &Tcod, game: &mut Game, objects: &mut [Object]) -> AI {
-    // A basic monster takes its turn
     let (monster_x, monster_y) = objects[monster_id].pos();
+    if let Some(fighter) = objects[monster_id].fighter.as_mut() {
+        if tcod.lights.is_visible(monster_x, monster_y) {
+            let verb = if fighter.alertness == Alertness::Asleep { "wakes up" } else { "notices you" };
+            game.messages.add(format!("The {} {}!", objects[monster_id].name, verb), ORANGE);
+        }
+        fighter.alertness = Alertness::Alert;
+    }
+    true
+}
 
-    // If you can see it, it can see you
-    if tcod.fov.is_in_fov(monster_x, monster_y) {
-        if objects[monster_id].distance_to(&objects[PLAYER]) >= 2.0 {
-            // Move towards player if far away
-            let (player_x, player_y) = objects[PLAYER].pos();
-            move_towards(monster_id, player_x, player_y, &game.map, objects);
-        } else if objects[PLAYER].fighter.map_or(false, |f| f.hp > 0) {
-            // Close enough, attack! (if the player is still alive.)
-            let (monster, player) = mut_two(monster_id, PLAYER, objects);
-            monster.attack(player, game);
+/// Make a noise that carries through the passable tiles around it. Sleeping monsters within
+/// earshot wake up, and those that aren't busy fighting or fleeing come over to investigate.
+fn make_noise(noise: Noise, x: i32, y: i32, tcod: &Tcod, game: &mut Game, objects: &mut [Object]) {
+    let map = &game.map;
+    let loudness = noise.loudness();
+    let field = DijkstraMap::bounded(map, &[(x, y)], loudness, |x, y| !map[x as usize][y as usize].blocked);
+
+    // Let the player know, if they can't see what's going on
+    let (player_x, player_y) = objects[PLAYER].pos();
+    if !tcod.lights.is_visible(x, y) && field.distance(player_x, player_y) <= loudness {
+        game.messages.add(format!("You hear {}.", noise.description()), LIGHT_GREY);
+    }
+
+    for id in 0..objects.len() {
+        let (monster_x, monster_y) = objects[id].pos();
+        if id == PLAYER || objects[id].fighter.is_none() || field.distance(monster_x, monster_y) > loudness {
+            continue;
+        }
+        if let Some(fighter) = objects[id].fighter.as_mut() {
+            if fighter.alertness == Alertness::Asleep {
+                fighter.alertness = Alertness::Wandering;
+            }
+        }
+        let investigates = match objects[id].ai {
+            Some(AI::Wander) | Some(AI::Search { .. }) | Some(AI::Return) | Some(AI::Hunt { .. }) => true,
+            Some(AI::Basic) => !player_in_sight(id, &game.map, objects),
+            _ => false,
+        };
+        if investigates {
+            if let Some(fighter) = objects[id].fighter.as_mut() {
+                fighter.last_seen = Some((x, y));
+            }
+            objects[id].ai = Some(AI::Hunt { x, y });
         }
     }
+}
 
-    // Return Basic AI variant
-    AI::Basic
+// Whether nothing blocks the view between two positions
+fn has_line_of_sight(from: (i32, i32), to: (i32, i32), map: &Map) -> bool {
+    Line::new(from, to).all(|(x, y)| (x, y) == to || !map[x as usize][y as usize].block_sight)
+}
+
+// Roll whether a monster notices the player: the closer the likelier,
+// while sneaky players and sleeping monsters make it less likely
+fn notices_player(monster_id: usize, game: &Game, objects: &[Object]) -> bool {
+    let distance = objects[monster_id].distance_to(&objects[PLAYER]) as i32;
+    let mut chance = NOTICE_BASE_CHANCE - distance * NOTICE_DISTANCE_PENALTY - objects[PLAYER].stealth(game);
+    if objects[monster_id].fighter.map_or(false, |f| f.alertness == Alertness::Asleep) {
+        chance /= SLEEP_NOTICE_DIVISOR;
+    }
+    rand::thread_rng().gen_range(0, 100) < cmp::max(chance, NOTICE_MIN_CHANCE)
+}
+
+// Take a step in a random direction, steering clear of walls and traps
+fn wander(id: usize, map: &Map, objects: &mut [Object]) {
+    let (dx, dy) = (rand::thread_rng().gen_range(-1, 2), rand::thread_rng().gen_range(-1, 2));
+    let (x, y) = (objects[id].x + dx, objects[id].y + dy);
+    if !is_known_trap(id, x, y, objects) {
+        move_by(id, dx, dy, map, objects);
+    }
 }
 
 fn ai_confused(
     monster_id: usize,
-    _tcod: &Tcod,
+    tcod: &Tcod,
     game: &mut Game,
     objects: &mut [Object],
     previous_ai: Box<AI>,
@@ -1092,14 +4723,17 @@ fn ai_confused(
 ) -> AI {
     if num_turns >= 0 {
         // Monster still in confused state!
-        // Move in a random direction
-        move_by(
-            monster_id,
-            rand::thread_rng().gen_range(-1, 2),
-            rand::thread_rng().gen_range(-1, 2),
-            &game.map,
-            objects,
-        );
+        // Move in a random direction, hitting whoever it stumbles into
+        let dx = rand::thread_rng().gen_range(-1, 2);
+        let dy = rand::thread_rng().gen_range(-1, 2);
+        let (x, y) = (objects[monster_id].x + dx, objects[monster_id].y + dy);
+        let target_id = objects
+            .iter()
+            .position(|object| object.fighter.is_some() && object.pos() == (x, y));
+        match target_id {
+            Some(target_id) if target_id != monster_id => melee(monster_id, target_id, tcod, game, objects),
+            _ => move_by(monster_id, dx, dy, &game.map, objects),
+        }
 
         // Return a Confused AI with a decreased number of turns
         AI::Confused {
@@ -1130,14 +4764,7 @@ fn player_death(player: &mut Object, game: &mut Game) {
 fn monster_death(monster: &mut Object, game: &mut Game) {
     // Transform it into a nasty corpse!
     // It doesn't block, can't be attacked and doesn't move
-    game.messages.add(
-        format!(
-            "{} is dead! You gain {} experience points.",
-            monster.name,
-            monster.fighter.unwrap().xp
-        ),
-        ORANGE,
-    );
+    game.messages.add(format!("{} is dead!", monster.name), ORANGE);
     monster.char = '%';
     monster.color = DARK_RED;
     monster.blocks = false;
@@ -1146,6 +4773,49 @@ fn monster_death(monster: &mut Object, game: &mut Game) {
     monster.name = format!("remains of {}", monster.name);
 }
 
+fn ally_death(ally: &mut Object, game: &mut Game) {
+    // The player loses a friend
+    game.messages.add(format!("Your {} is killed!", ally.name), LIGHT_RED);
+    ally.char = '%';
+    ally.color = DARK_RED;
+    ally.blocks = false;
+    ally.fighter = None;
+    ally.ai = None;
+    ally.name = format!("remains of {}", ally.name);
+}
+
+// Center the camera on the given map position, without scrolling past the map edges
+fn move_camera(tcod: &mut Tcod, map: &Map, target_x: i32, target_y: i32) {
+    let (map_width, map_height) = map_size(map);
+
+    // New camera coordinates (top-left corner of the view, relative to the map)
+    let x = target_x - CAMERA_WIDTH / 2;
+    let y = target_y - CAMERA_HEIGHT / 2;
+
+    // Make sure the camera doesn't see outside the map.
+    // Maps smaller than the view just stay in the top-left corner.
+    let x = cmp::max(0, cmp::min(x, map_width - CAMERA_WIDTH));
+    let y = cmp::max(0, cmp::min(y, map_height - CAMERA_HEIGHT));
+
+    tcod.camera = (x, y);
+}
+
+// Convert map coordinates to screen coordinates, if they are inside the camera view
+fn to_camera_coordinates(tcod: &Tcod, x: i32, y: i32) -> Option<(i32, i32)> {
+    let (x, y) = (x - tcod.camera.0, y - tcod.camera.1);
+    if x < 0 || y < 0 || x >= CAMERA_WIDTH || y >= CAMERA_HEIGHT {
+        // Outside the camera view
+        None
+    } else {
+        Some((x, y))
+    }
+}
+
+// Convert screen coordinates to map coordinates
+fn to_map_coordinates(tcod: &Tcod, x: i32, y: i32) -> (i32, i32) {
+    (x + tcod.camera.0, y + tcod.camera.1)
+}
+
 fn render_bar(
     panel: &mut Offscreen,
     x: i32,
@@ -1182,26 +4852,125 @@ fn render_bar(
     );
 }
 
+// Return the brightest light the player has equipped
+fn player_light(inventory: &[Object]) -> Option<Light> {
+    inventory
+        .iter()
+        .filter(|item| item.equipment.map_or(false, |e| e.equipped))
+        .filter_map(|item| item.light)
+        .max_by_key(|light| light.radius)
+}
+
+/// Work out the light falling on every tile, and which tiles the player can see:
+/// the lit tiles in line of sight, and those right next to the player
+fn update_lighting(tcod: &mut Tcod, game: &Game, objects: &[Object]) {
+    let (map_width, map_height) = map_size(&game.map);
+    let index = |x: i32, y: i32| (x + y * map_width) as usize;
+
+    // Lit rooms are bright all over
+    let mut light = vec![BLACK; (map_width * map_height) as usize];
+    for x in 0..map_width {
+        for y in 0..map_height {
+            if game.map[x as usize][y as usize].lit {
+                light[index(x, y)] = COLOR_AMBIENT_LIGHT;
+            }
+        }
+    }
+
+    // Gather the light sources: braziers and glowing monsters (but not items lying around),
+    // fires, and whatever light the player carries
+    let (player_x, player_y) = objects[PLAYER].pos();
+    let mut sources: Vec<_> = objects
+        .iter()
+        .filter(|object| object.item.is_none())
+        .filter_map(|object| object.light.map(|light| (object.x, object.y, light)))
+        .collect();
+    sources.extend(game.hazards.iter().filter(|hazard| hazard.kind == HazardKind::Fire).map(|hazard| {
+        let light = Light {
+            radius: FIRE_LIGHT_RADIUS,
+            color: FLAME,
+            fuel: None,
+        };
+        (hazard.x, hazard.y, light)
+    }));
+    if let Some(light) = player_light(&game.inventory) {
+        sources.push((player_x, player_y, light));
+    }
+
+    // Spread each light through its own field of view, fading out with distance
+    for (source_x, source_y, source) in sources {
+        tcod.light_fov.compute_fov(source_x, source_y, source.radius, FOV_LIGHT_WALLS, FOV_ALGO);
+        for x in cmp::max(source_x - source.radius, 0)..cmp::min(source_x + source.radius + 1, map_width) {
+            for y in cmp::max(source_y - source.radius, 0)..cmp::min(source_y + source.radius + 1, map_height) {
+                if !tcod.light_fov.is_in_fov(x, y) {
+                    continue;
+                }
+                let distance = (((x - source_x).pow(2) + (y - source_y).pow(2)) as f32).sqrt();
+                let intensity = 1.0 - distance / (source.radius + 1) as f32;
+                if intensity > 0.0 {
+                    light[index(x, y)] = light[index(x, y)] + source.color * intensity;
+                }
+            }
+        }
+    }
+
+    // The player can see what is lit, and feel their way around in the dark
+    let visible = (0..map_width * map_height)
+        .map(|i| {
+            let (x, y) = (i % map_width, i / map_width);
+            let nearby = cmp::max((x - player_x).abs(), (y - player_y).abs()) <= DARKVISION_RADIUS;
+            tcod.fov.is_in_fov(x, y) && (light[i as usize] != BLACK || nearby)
+        })
+        .collect();
+
+    tcod.lights = LightMap {
+        width: map_width,
+        light,
+        visible,
+    };
+}
+
 fn render_all(tcod: &mut Tcod, game: &mut Game, objects: &[Object], fov_recompute: bool) {
     // Recompute FOV if needed (eg. the player moved )
     if fov_recompute {
         let player = &objects[PLAYER];
-        tcod.fov.compute_fov(player.x, player.y, TORCH_RADIUS, FOV_LIGHT_WALLS, FOV_ALGO);
+        tcod.fov.compute_fov(player.x, player.y, FOV_RADIUS, FOV_LIGHT_WALLS, FOV_ALGO);
     }
+
+    // Lights move around and burn out, so work out what's lit every time
+    update_lighting(tcod, game, objects);
     
-    // Go through all tiles, and set their background color
-    for y in 0..MAP_HEIGHT {
-        for x in 0..MAP_WIDTH {
+    // Keep the player in the middle of the camera view
+    let (player_x, player_y) = objects[PLAYER].pos();
+    move_camera(tcod, &game.map, player_x, player_y);
+    let (camera_x, camera_y) = tcod.camera;
+    let (map_width, map_height) = map_size(&game.map);
+
+    // Get the wall/ ground palette of the level's theme
+    let theme_data = game.theme.data();
+
+    // Go through all tiles in the camera view, and set their background color
+    for screen_y in 0..CAMERA_HEIGHT {
+        for screen_x in 0..CAMERA_WIDTH {
+            // Get the map position shown at this screen position
+            let (x, y) = (screen_x + camera_x, screen_y + camera_y);
+            if x < 0 || y < 0 || x >= map_width || y >= map_height {
+                continue;
+            }
+
             // Get visible state of tile
-            let visible = tcod.fov.is_in_fov(x, y);
+            let visible = tcod.lights.is_visible(x, y);
             let wall = game.map[x as usize][y as usize].block_sight;
-            let color = match (visible, wall) {
-                // Outside of field of view:
-                (false, true) => COLOR_DARK_WALL,
-                (false, false) => COLOR_DARK_GROUND,
-                // Inside fov:
-                (true, true) => COLOR_LIGHT_WALL,
-                (true, false) => COLOR_LIGHT_GROUND,
+            let (dark, light) = if wall {
+                (theme_data.dark_wall, theme_data.light_wall)
+            } else {
+                (theme_data.dark_ground, theme_data.light_ground)
+            };
+            // Tint visible tiles by the color and intensity of the light falling on them
+            let color = if visible {
+                dark + light * tcod.lights.light(x, y)
+            } else {
+                dark
             };
 
             // Get explored state of tile
@@ -1213,7 +4982,53 @@ fn render_all(tcod: &mut Tcod, game: &mut Game, objects: &[Object], fov_recomput
 
             // Show explored tiles only (any visible tile is explored already)
             if *explored {
-                tcod.con.set_char_background(x, y, color, BackgroundFlag::Set);
+                tcod.con.set_char_background(screen_x, screen_y, color, BackgroundFlag::Set);
+
+                // Draw found doors and debris on top of the ground
+                let tile = &game.map[x as usize][y as usize];
+                if tile.door && !tile.hidden {
+                    tcod.con.set_char(screen_x, screen_y, '+');
+                    tcod.con.set_char_foreground(screen_x, screen_y, theme_data.light_wall);
+                } else if tile.flammable {
+                    tcod.con.set_char(screen_x, screen_y, '"');
+                    tcod.con.set_char_foreground(screen_x, screen_y, COLOR_DEBRIS);
+                }
+            }
+        }
+    }
+
+    // Draw fire and gas as an overlay on the visible tiles
+    for hazard in &game.hazards {
+        if !tcod.lights.is_visible(hazard.x, hazard.y) {
+            continue;
+        }
+        if let Some((screen_x, screen_y)) = to_camera_coordinates(tcod, hazard.x, hazard.y) {
+            let (overlay, max_strength) = match hazard.kind {
+                HazardKind::Fire => (FLAME, FIRE_DURATION),
+                HazardKind::PoisonGas => (LIGHT_GREEN, GAS_CLOUD_STRENGTH),
+                HazardKind::ConfusionGas => (LIGHT_VIOLET, GAS_CLOUD_STRENGTH),
+            };
+            // Stronger hazards are more opaque
+            let amount = 0.3 + 0.6 * cmp::min(hazard.strength, max_strength) as f32 / max_strength as f32;
+            let background = tcod.con.get_char_background(screen_x, screen_y);
+            tcod.con.set_char_background(
+                screen_x,
+                screen_y,
+                colors::lerp(background, overlay, amount),
+                BackgroundFlag::Set,
+            );
+        }
+    }
+
+    // Draw the flight paths of the latest projectiles
+    for shot in &game.shots {
+        for &(x, y) in &shot.path {
+            if !tcod.lights.is_visible(x, y) {
+                continue;
+            }
+            if let Some((screen_x, screen_y)) = to_camera_coordinates(tcod, x, y) {
+                tcod.con.set_char(screen_x, screen_y, '*');
+                tcod.con.set_char_foreground(screen_x, screen_y, shot.color);
             }
         }
     }
@@ -1222,24 +5037,27 @@ fn render_all(tcod: &mut Tcod, game: &mut Game, objects: &[Object], fov_recomput
     let mut to_draw: Vec<_> = objects
         .iter()
         .filter(|o| {
-            tcod.fov.is_in_fov(o.x, o.y)
+            tcod.lights.is_visible(o.x, o.y)
             || (o.always_visible && game.map[o.x as usize][o.y as usize].explored)
         })
+        .filter(|o| o.trap.map_or(true, |trap| trap.revealed))
         .collect();
     
     // Sort so that non-blocking objects come first
     to_draw.sort_by(|o1, o2| { o1.blocks.cmp(&o2.blocks) });
     
-    // Draw the objects in the list
+    // Draw the objects in the list that are inside the camera view
     for object in &to_draw {
-        object.draw(&mut tcod.con);
+        if let Some((screen_x, screen_y)) = to_camera_coordinates(tcod, object.x, object.y) {
+            object.draw(&mut tcod.con, screen_x, screen_y);
+        }
     }
     
     // Add the contents of con to the root console
     console::blit(
         &tcod.con,
         (0, 0),
-        (MAP_WIDTH, MAP_HEIGHT),
+        (CAMERA_WIDTH, CAMERA_HEIGHT),
         &mut tcod.root,
         (0, 0),
         1.0,
@@ -1266,12 +5084,26 @@ fn render_all(tcod: &mut Tcod, game: &mut Game, objects: &[Object], fov_recomput
     );
 
     // Render dungeon information (lvl, etc.)
+    let location = if game.dungeon_level == 0 {
+        "Town".to_string()
+    } else {
+        format!("Dungeon level: {}", game.dungeon_level)
+    };
     tcod.panel.print_ex(
         1,
         3,
         BackgroundFlag::None,
         TextAlignment::Left,
-        format!("Dungeon level: {}", game.dungeon_level),
+        location,
+    );
+
+    // Render the player's gold
+    tcod.panel.print_ex(
+        1,
+        4,
+        BackgroundFlag::None,
+        TextAlignment::Left,
+        format!("Gold: {}", game.gold),
     );
 
     // Render the names of objects under the mouse
@@ -1281,7 +5113,7 @@ fn render_all(tcod: &mut Tcod, game: &mut Game, objects: &[Object], fov_recomput
         0,
         BackgroundFlag::None,
         TextAlignment::Left,
-        get_names_under_mouse(tcod.mouse, objects, &tcod.fov),
+        get_names_under_mouse(tcod.mouse, tcod.camera, objects, &tcod.lights),
     );
 
     // Render the game messages, one line at a time,
@@ -1318,13 +5150,20 @@ fn render_all(tcod: &mut Tcod, game: &mut Game, objects: &[Object], fov_recomput
 }
 
 // Return a string with the names of all objects under the mouse
-fn get_names_under_mouse(mouse: Mouse, objects: &[Object], fov_map: &FovMap) -> String {
-    let (x, y) = (mouse.cx as i32, mouse.cy as i32);
+fn get_names_under_mouse(mouse: Mouse, camera: (i32, i32), objects: &[Object], lights: &LightMap) -> String {
+    // Ignore the mouse when it's over the GUI panel
+    if mouse.cy as i32 >= CAMERA_HEIGHT {
+        return String::new();
+    }
+
+    // Translate the mouse position to map coordinates
+    let (x, y) = (mouse.cx as i32 + camera.0, mouse.cy as i32 + camera.1);
 
     // Create a list with the names of all objects at the mouse's coordinates and in FOV
     let names = objects
         .iter()
-        .filter(|obj| obj.pos() == (x, y) && fov_map.is_in_fov(obj.x, obj.y))
+        .filter(|obj| obj.pos() == (x, y) && lights.is_visible(obj.x, obj.y))
+        .filter(|obj| obj.trap.map_or(true, |trap| trap.revealed))
         .map(|obj| obj.name.clone())
         .collect::<Vec<_>>();
 
@@ -1332,50 +5171,166 @@ fn get_names_under_mouse(mouse: Mouse, objects: &[Object], fov_map: &FovMap) ->
     names.join(", ") 
 }
 
+// Start walking to a tile the player has explored, along the tiles they know to be clear
+fn travel_to(x: i32, y: i32, game: &mut Game, objects: &[Object]) {
+    let (map_width, map_height) = map_size(&game.map);
+    if x < 0 || y < 0 || x >= map_width || y >= map_height || !game.map[x as usize][y as usize].explored {
+        game.messages.add("You don't know what's there.", WHITE);
+        return;
+    }
+
+    let map = &game.map;
+    let field = DijkstraMap::new(map, &[(x, y)], |x, y| {
+        let tile = &map[x as usize][y as usize];
+        tile.explored && !tile.blocked && !is_known_trap(PLAYER, x, y, objects)
+    });
+    let (player_x, player_y) = objects[PLAYER].pos();
+    if field.distance(player_x, player_y) == i32::MAX {
+        game.messages.add("You don't know a way there.", WHITE);
+        return;
+    }
+    game.auto_move = Some(AutoMove::Travel { destination: (x, y), field });
+}
+
+/// Take the next step of whatever the player is doing automatically,
+/// stopping as soon as something needs their attention
+fn auto_move(tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) -> PlayerAction {
+    // Stop when a monster comes into view
+    let monster = objects
+        .iter()
+        .find(|object| is_enemy(object, &objects[PLAYER]) && tcod.lights.is_visible(object.x, object.y));
+    if let Some(monster) = monster {
+        game.messages.add(format!("You see a {}.", monster.name), WHITE);
+        game.auto_move = None;
+        return PlayerAction::DidntTakeTurn;
+    }
+
+    // A confused player can't keep a steady course
+    if objects[PLAYER].fighter.map_or(false, |f| f.confused_turns > 0) {
+        game.messages.add("You are too confused to find your way.", LIGHT_CYAN);
+        game.auto_move = None;
+        return PlayerAction::DidntTakeTurn;
+    }
+
+    match game.auto_move.take() {
+        Some(AutoMove::Explore { seen_items }) => {
+            // Stop when a new item comes into view
+            let item = objects.iter().find(|object| {
+                object.item.is_some()
+                    && tcod.lights.is_visible(object.x, object.y)
+                    && !seen_items.contains(&object.pos())
+            });
+            if let Some(item) = item {
+                game.messages.add(format!("You see a {}.", item.name), WHITE);
+                return PlayerAction::DidntTakeTurn;
+            }
+
+            // Head for the nearest unexplored tile
+            update_explore_map(game, objects);
+            let explore = &game.dijkstra.explore;
+            if !move_downhill(PLAYER, &game.map, objects, |x, y| explore.distance(x, y)) {
+                game.messages.add("There is nowhere left to explore.", WHITE);
+                return PlayerAction::DidntTakeTurn;
+            }
+            game.auto_move = Some(AutoMove::Explore { seen_items });
+            PlayerAction::TookTurn
+        }
+        Some(AutoMove::Travel { destination, field }) => {
+            // Stop on arrival, or when the way is blocked
+            if objects[PLAYER].pos() == destination {
+                return PlayerAction::DidntTakeTurn;
+            }
+            if !move_downhill(PLAYER, &game.map, objects, |x, y| field.distance(x, y)) {
+                game.messages.add("Something is in your way.", WHITE);
+                return PlayerAction::DidntTakeTurn;
+            }
+            game.auto_move = Some(AutoMove::Travel { destination, field });
+            PlayerAction::TookTurn
+        }
+        None => PlayerAction::DidntTakeTurn,
+    }
+}
+
 // Handle key input
 fn handle_keys(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) -> PlayerAction {    
     // Get status of player
     let player_alive = objects[PLAYER].alive;
     
+    // Travel to the tile that was clicked on
+    if tcod.mouse.lbutton_pressed && player_alive && (tcod.mouse.cy as i32) < CAMERA_HEIGHT {
+        let (x, y) = to_map_coordinates(tcod, tcod.mouse.cx as i32, tcod.mouse.cy as i32);
+        travel_to(x, y, game, objects);
+        return PlayerAction::DidntTakeTurn;
+    }
+
     // Determine which key was pressed
     match (tcod.key, tcod.key.text(), player_alive) {
         // Movement keys
         (Key { code: KeyCode::Up, .. }, _, true) | (Key { code: KeyCode::NumPad8, .. }, _, true) => {
-            player_move_or_attack(0, -1, game, objects);
+            player_move_or_attack(0, -1, tcod, game, objects);
             return PlayerAction::TookTurn
         }
         (Key { code: KeyCode::Down, .. }, _, true) | (Key { code: KeyCode::NumPad2, .. }, _, true) => {
-            player_move_or_attack(0, 1, game, objects);
+            player_move_or_attack(0, 1, tcod, game, objects);
             return PlayerAction::TookTurn
         }
         (Key { code: KeyCode::Left, .. }, _, true) | (Key { code: KeyCode::NumPad4, .. }, _, true) => {
-            player_move_or_attack(-1, 0, game, objects);
+            player_move_or_attack(-1, 0, tcod, game, objects);
             return PlayerAction::TookTurn;
         }
         (Key { code: KeyCode::Right, .. }, _, true) | (Key { code: KeyCode::NumPad6, .. }, _, true) => {
-            player_move_or_attack(1, 0, game, objects);
+            player_move_or_attack(1, 0, tcod, game, objects);
             return PlayerAction::TookTurn;
         }
         (Key { code: KeyCode::Home, .. }, _, true) | (Key { code: KeyCode::NumPad7, .. }, _, true) => {
-            player_move_or_attack(-1, -1, game, objects);
+            player_move_or_attack(-1, -1, tcod, game, objects);
             return PlayerAction::TookTurn;
         }
         (Key { code: KeyCode::PageUp, .. }, _, true) | (Key { code: KeyCode::NumPad9, .. }, _, true) => {
-            player_move_or_attack(1, -1, game, objects);
+            player_move_or_attack(1, -1, tcod, game, objects);
             return PlayerAction::TookTurn;
         }
         (Key { code: KeyCode::End, .. }, _, true) | (Key { code: KeyCode::NumPad1, .. }, _, true) => {
-            player_move_or_attack(-1, 1, game, objects);
+            player_move_or_attack(-1, 1, tcod, game, objects);
             return PlayerAction::TookTurn;
         }
         (Key { code: KeyCode::PageDown, .. }, _, true) | (Key { code: KeyCode::NumPad3, .. }, _, true) => {
-            player_move_or_attack(1, 1, game, objects);
+            player_move_or_attack(1, 1, tcod, game, objects);
             return PlayerAction::TookTurn;
         }
         (Key { code: KeyCode::NumPad5, .. }, _, true) => {
             // Sleep, i.e. don't moave, wait for the monster(s) to come to you
             return PlayerAction::TookTurn; 
         }
+        (Key { code: KeyCode::Text, .. }, "s", true) => {
+            // Search nearby tiles for hidden things
+            search(tcod, game, objects);
+            return PlayerAction::TookTurn;
+        }
+        (Key { code: KeyCode::Text, .. }, "x", true) => {
+            // Explore automatically, ignoring the items already in view
+            let seen_items = objects
+                .iter()
+                .filter(|object| object.item.is_some() && tcod.lights.is_visible(object.x, object.y))
+                .map(Object::pos)
+                .collect();
+            game.auto_move = Some(AutoMove::Explore { seen_items });
+            return PlayerAction::DidntTakeTurn;
+        }
+        (Key { code: KeyCode::Text, .. }, "t", true) => {
+            // Travel to the stairs down, if the player has found them
+            let stairs = objects.iter().find(|object| {
+                object.name == "stairs" && game.map[object.x as usize][object.y as usize].explored
+            });
+            match stairs.map(Object::pos) {
+                Some(position) if position == objects[PLAYER].pos() => {
+                    game.messages.add("You are already on the stairs.", WHITE)
+                }
+                Some((x, y)) => travel_to(x, y, game, objects),
+                None => game.messages.add("You don't know where the stairs are.", WHITE),
+            }
+            return PlayerAction::DidntTakeTurn;
+        }
         (Key { code: KeyCode::Text, .. }, "g", true) => {
             // Pick up an item
             let item_id = objects
@@ -1421,6 +5376,22 @@ fn handle_keys(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) -> P
             }
             return PlayerAction::DidntTakeTurn;
         }
+        (Key { code: KeyCode::Text, .. }, ">", true) => {
+            // Go up stairs, if the player is on them
+            let player_on_up_stairs = objects
+                .iter()
+                .any(|object| object.pos() == objects[PLAYER].pos() && object.name == "up stairs");
+            if player_on_up_stairs {
+                previous_level(tcod, game, objects);
+
+                // Making it back to town with the crown wins the game
+                let has_crown = game.inventory.iter().any(|item| item.item == Some(Item::Crown));
+                if game.dungeon_level == 0 && has_crown {
+                    return PlayerAction::Won;
+                }
+            }
+            return PlayerAction::DidntTakeTurn;
+        }
         (Key { code: KeyCode::Text, .. }, "c", true) => {
             // Show character information
             let player = &objects[PLAYER];
@@ -1450,6 +5421,17 @@ Defense: {}",
             }  
             return PlayerAction::DidntTakeTurn;
         }
+        (Key { code: KeyCode::F2, .. }, _, _) => {
+            // Dump the current map, for debugging the generators
+            match dump_map(&game.map, objects, game.dungeon_level, game.seed, game.theme) {
+                Ok(()) => game.messages.add(
+                    format!("Map dumped to {} and {}.", MAP_DUMP_TEXT_FILE, MAP_DUMP_JSON_FILE),
+                    WHITE,
+                ),
+                Err(e) => game.messages.add(format!("Could not dump the map: {}", e), RED),
+            }
+            return PlayerAction::DidntTakeTurn;
+        }
         (Key { code: KeyCode::Enter, alt: true, .. }, _, _,) => {
             // Alt+Enter: toggle fullscreen
             let fullscreen = tcod.root.is_fullscreen();
@@ -1569,23 +5551,34 @@ fn inventory_menu(inventory: &[Object], header: &str, root: &mut Root) -> Option
     }
 }
 
-fn use_item(inventory_id: usize, tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) {
+fn use_item(inventory_id: usize, tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) {
     // Just call the "use_function" if it is defined
     if let Some(item) = game.inventory[inventory_id].item {
-        let on_use = match item {
-            Item::Heal => cast_heal,
-            Item::Lightning => cast_lightning,
-            Item::Confuse => cast_confuse,
-            Item::Fireball => cast_fireball,
-            Item::Sword => toggle_equipment,
-            Item::Shield => toggle_equipment,
+        let result = match item {
+            Item::Heal => cast_heal(inventory_id, tcod, game, objects),
+            Item::Lightning => cast_lightning(inventory_id, tcod, game, objects),
+            Item::Confuse => cast_confuse(inventory_id, tcod, game, objects),
+            Item::Fireball => cast_fireball(inventory_id, tcod, game, objects),
+            Item::Sword => toggle_equipment(inventory_id, tcod, game, objects),
+            Item::Shield => toggle_equipment(inventory_id, tcod, game, objects),
+            Item::Pickaxe => toggle_equipment(inventory_id, tcod, game, objects),
+            Item::DigWand => cast_dig(inventory_id, tcod, game, objects),
+            Item::Torch => toggle_equipment(inventory_id, tcod, game, objects),
+            Item::Lantern => toggle_equipment(inventory_id, tcod, game, objects),
+            Item::Haste => cast_haste(inventory_id, tcod, game, objects),
+            Item::Slow => cast_slow(inventory_id, tcod, game, objects),
+            Item::WarAxe => toggle_equipment(inventory_id, tcod, game, objects),
+            // Summoning adds creatures to the level, so it gets the whole list of objects
+            Item::Summon => cast_summon(inventory_id, tcod, game, objects),
+            Item::Crown => toggle_equipment(inventory_id, tcod, game, objects),
         };
-        match on_use(inventory_id, tcod, game, objects) {
+        match result {
             UseResult::UsedUp => {
                 // Destroy after use, unless it was cancelled for some reason
                 game.inventory.remove(inventory_id);
+                spend_energy(&mut objects[PLAYER], USE_ITEM_COST);
             }
-            UseResult::UsedAndKept => {} // Do nothing
+            UseResult::UsedAndKept => spend_energy(&mut objects[PLAYER], USE_ITEM_COST),
             UseResult::Cancelled => {
                 game.messages.add("Cancelled", WHITE);
             }
@@ -1622,11 +5615,17 @@ fn target_tile(
         // This erases the inventory and shows the names of objects under the mouse.
         tcod.root.flush();
 
-        // Get (x, y) coordinates of the mouse
-        let (x, y) = (tcod.mouse.cx as i32, tcod.mouse.cy as i32);
+        // Get (x, y) map coordinates of the mouse
+        let (screen_x, screen_y) = (tcod.mouse.cx as i32, tcod.mouse.cy as i32);
+        let (x, y) = to_map_coordinates(tcod, screen_x, screen_y);
 
         // Accept the target if the player clicked in FOV,
-        let in_fov = (x < MAP_WIDTH) && (y < MAP_HEIGHT) && tcod.fov.is_in_fov(x, y);
+        let (map_width, map_height) = map_size(&game.map);
+        let in_fov = (screen_x < CAMERA_WIDTH)
+            && (screen_y < CAMERA_HEIGHT)
+            && (x < map_width)
+            && (y < map_height)
+            && tcod.lights.is_visible(x, y);
         // and in case a range is specified, if it's in that range
         let in_range = max_range.map_or(true, |range| objects[PLAYER].distance(x, y) <= range);
         if tcod.mouse.lbutton_pressed && in_fov && in_range {
@@ -1664,22 +5663,17 @@ fn target_monster(
 
 fn cast_heal(
     _inventory_id: usize,
-    _tcod: &mut Tcod,
+    tcod: &mut Tcod,
     game: &mut Game,
     objects: &mut [Object],
 ) -> UseResult {
     // Heal the player
-    let player = &mut objects[PLAYER];
-    if let Some(fighter) = player.fighter {
-        if fighter.hp == player.max_hp(game) {
-            game.messages.add("You are already at full health.", RED);
-            return UseResult::Cancelled;
-        }
-        game.messages.add("Your wounds start to feel better!", LIGHT_VIOLET);
-        player.heal(HEAL_AMOUNT, game);
-        return UseResult::UsedUp;
+    if heal_effect(PLAYER, PLAYER, tcod, game, objects) {
+        UseResult::UsedUp
+    } else {
+        game.messages.add("You are already at full health.", RED);
+        UseResult::Cancelled
     }
-    UseResult::Cancelled
 }
 
 fn cast_lightning(
@@ -1692,20 +5686,7 @@ fn cast_lightning(
     let monster_id = closest_monster(tcod, objects, LIGHTNING_RANGE);
     if let Some(monster_id) = monster_id {
         // Zap it!
-        game.messages.add(
-            format!(
-                "A lightning bolt strikes the {} with a loud thunder! \
-                 The damage is {} hit points.",
-                objects[monster_id].name, LIGHTNING_DAMAGE
-            ),
-            LIGHT_BLUE,
-        );
-        
-        // Assign damage to target and check if xp is returned for killing target
-        if let Some(xp) = objects[monster_id].take_damage(LIGHTNING_DAMAGE, game) {
-            // Yield experience to the player
-            objects[PLAYER].fighter.as_mut().unwrap().xp += xp;
-        }
+        lightning_effect(PLAYER, monster_id, tcod, game, objects);
 
         // Return UsedUp result
         UseResult::UsedUp
@@ -1728,25 +5709,141 @@ fn cast_confuse(
     game.messages.add("Left-click an enemy to confuse it, or right-click to cancel.", LIGHT_CYAN);
     let monster_id = target_monster(tcod, game, objects, Some(CONFUSE_RANGE as f32));
     if let Some(monster_id) = monster_id {
-        let old_ai = objects[monster_id].ai.take().unwrap_or(AI::Basic);
-        // Replace the monster's AI with a "confused" one
-        // that will restore the old AI after some turns
-        objects[monster_id].ai = Some(AI::Confused {
-            previous_ai: Box::new(old_ai),
-            num_turns: CONFUSE_NUM_TURNS,
-        });
+        confuse_effect(PLAYER, monster_id, tcod, game, objects);
+        UseResult::UsedUp
+    } else {
+        // Cancel the action
+        game.messages.add("Saving it for later, eh?  Good choice!", WHITE);
+        UseResult::Cancelled
+    }
+}
+
+fn cast_haste(
+    _inventory_id: usize,
+    _tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+) -> UseResult {
+    // Speed the player up
+    haste(&mut objects[PLAYER], HASTE_TURNS);
+    game.messages.add("You feel yourself speed up!", LIGHT_CYAN);
+    UseResult::UsedUp
+}
+
+fn cast_slow(
+    _inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+) -> UseResult {
+    // Ask the player for a target to slow down
+    game.messages.add("Left-click an enemy to slow it, or right-click to cancel.", LIGHT_CYAN);
+    let monster_id = target_monster(tcod, game, objects, Some(CONFUSE_RANGE as f32));
+    if let Some(monster_id) = monster_id {
+        slow(&mut objects[monster_id], SLOW_TURNS);
+        game.messages.add(format!("The {} slows down to a crawl!", objects[monster_id].name), LIGHT_GREEN);
+        UseResult::UsedUp
+    } else {
+        // Cancel the action
+        game.messages.add("Saving it for later, eh?  Good choice!", WHITE);
+        UseResult::Cancelled
+    }
+}
+
+fn cast_summon(
+    _inventory_id: usize,
+    _tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) -> UseResult {
+    // Call up a spirit wolf next to the player
+    let (player_x, player_y) = objects[PLAYER].pos();
+    match free_tile_near(player_x, player_y, &game.map, objects) {
+        Some((x, y)) => {
+            objects.push(create_ally("spirit wolf", x, y));
+            game.messages.add("A spirit wolf appears at your side!", LIGHT_AZURE);
+            UseResult::UsedUp
+        }
+        None => {
+            game.messages.add("There is no room for anything to appear.", RED);
+            UseResult::Cancelled
+        }
+    }
+}
+
+/// Heal the wounds of a creature, on behalf of whoever drank the potion or cast the spell.
+/// Returns false without doing anything if the target is already at full health.
+fn heal_effect(caster_id: usize, target_id: usize, tcod: &Tcod, game: &mut Game, objects: &mut [Object]) -> bool {
+    let max_hp = objects[target_id].max_hp(game);
+    if objects[target_id].fighter.map_or(true, |f| f.hp >= max_hp) {
+        return false;
+    }
+
+    let (target_x, target_y) = objects[target_id].pos();
+    if target_id == PLAYER {
+        game.messages.add("Your wounds start to feel better!", LIGHT_VIOLET);
+    } else if tcod.lights.is_visible(target_x, target_y) {
+        let message = if caster_id == target_id {
+            format!("The {} heals its wounds.", objects[caster_id].name)
+        } else {
+            format!("The {} heals the {}.", objects[caster_id].name, objects[target_id].name)
+        };
+        game.messages.add(message, LIGHT_VIOLET);
+    }
+    objects[target_id].heal(HEAL_AMOUNT, game);
+    true
+}
+
+/// Strike a creature with a bolt of lightning. The thunder can be heard far away, and any
+/// experience for the kill goes to the caster.
+fn lightning_effect(caster_id: usize, target_id: usize, tcod: &Tcod, game: &mut Game, objects: &mut [Object]) {
+    let message = if target_id == PLAYER {
+        format!(
+            "The {} calls down a lightning bolt on you with a loud thunder! \
+             The damage is {} hit points.",
+            objects[caster_id].name, LIGHTNING_DAMAGE
+        )
+    } else {
+        format!(
+            "A lightning bolt strikes the {} with a loud thunder! \
+             The damage is {} hit points.",
+            objects[target_id].name, LIGHTNING_DAMAGE
+        )
+    };
+    game.messages.add(message, LIGHT_BLUE);
+    let (x, y) = objects[target_id].pos();
+    make_noise(Noise::Thunder, x, y, tcod, game, objects);
+
+    // Assign damage to target and check if xp is returned for killing target
+    if let Some(xp) = objects[target_id].take_damage(LIGHTNING_DAMAGE, game) {
+        // Yield experience to the caster
+        if let Some(fighter) = objects[caster_id].fighter.as_mut() {
+            fighter.xp += xp;
+        }
+    }
+}
+
+/// Confuse a creature for a while: monsters stumble around instead of following their AI,
+/// and the player's moves go in random directions.
+fn confuse_effect(caster_id: usize, target_id: usize, tcod: &Tcod, game: &mut Game, objects: &mut [Object]) {
+    confuse(&mut objects[target_id], CONFUSE_NUM_TURNS);
+    let (target_x, target_y) = objects[target_id].pos();
+    if target_id == PLAYER {
+        game.messages.add(
+            format!(
+                "The {} stares into your eyes. Your head spins, and you start to stumble around!",
+                objects[caster_id].name
+            ),
+            LIGHT_CYAN,
+        );
+    } else if tcod.lights.is_visible(target_x, target_y) {
         game.messages.add(
             format!(
                 "The eyes of {} look vacant, as he starts to stumble around!",
-                objects[monster_id].name
+                objects[target_id].name
             ),
             LIGHT_GREEN,
         );
-        UseResult::UsedUp
-    } else {
-        // Cancel the action
-        game.messages.add("Saving it for later, eh?  Good choice!", WHITE);
-        UseResult::Cancelled
     }
 }
 
@@ -1772,6 +5869,7 @@ fn cast_fireball(
         ),
         ORANGE,
     );
+    make_noise(Noise::Explosion, x, y, tcod, game, objects);
 
     // Create a counter to keep track of xp gained (if any)
     let mut xp_to_gain = 0;
@@ -1799,6 +5897,66 @@ fn cast_fireball(
     // Yield experience to the player
     objects[PLAYER].fighter.as_mut().unwrap().xp += xp_to_gain;
 
+    // Leave the area burning
+    let (map_width, map_height) = map_size(&game.map);
+    for fire_x in (x - FIREBALL_RADIUS)..(x + FIREBALL_RADIUS + 1) {
+        for fire_y in (y - FIREBALL_RADIUS)..(y + FIREBALL_RADIUS + 1) {
+            let in_radius = (((fire_x - x).pow(2) + (fire_y - y).pow(2)) as f32).sqrt() <= FIREBALL_RADIUS as f32;
+            let in_map = fire_x >= 0 && fire_y >= 0 && fire_x < map_width && fire_y < map_height;
+            if in_radius && in_map {
+                ignite(fire_x, fire_y, game);
+            }
+        }
+    }
+
+    // Return UsedUp result
+    UseResult::UsedUp
+}
+
+fn cast_dig(
+    _inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+) -> UseResult {
+    // Ask the player for a direction to dig in
+    game.messages.add(
+        "Left-click a tile to dig towards, or right-click to cancel.",
+        LIGHT_CYAN,
+    );
+    let (target_x, target_y) = match target_tile(tcod, game, objects, None) {
+        Some(tile_pos) => tile_pos,
+        None => return UseResult::Cancelled,
+    };
+    let (player_x, player_y) = objects[PLAYER].pos();
+    if (target_x, target_y) == (player_x, player_y) {
+        return UseResult::Cancelled;
+    }
+
+    // Extend the line towards the target up to the wand's range
+    let (dx, dy) = (target_x - player_x, target_y - player_y);
+    let distance = ((dx.pow(2) + dy.pow(2)) as f32).sqrt();
+    let end_x = player_x + (dx as f32 * DIG_WAND_RANGE as f32 / distance).round() as i32;
+    let end_y = player_y + (dy as f32 * DIG_WAND_RANGE as f32 / distance).round() as i32;
+
+    // Tunnel along the line, stopping at the boundary of the map
+    let mut dug = 0;
+    for (x, y) in Line::new((player_x, player_y), (end_x, end_y)) {
+        if is_boundary(x, y, &game.map) {
+            break;
+        }
+        if dig(x, y, tcod, game) {
+            dug += 1;
+        }
+    }
+    game.messages.add(
+        format!("A beam of force bores through {} tiles of rock!", dug),
+        SEPIA,
+    );
+
+    // Look down the new tunnel
+    tcod.fov.compute_fov(player_x, player_y, FOV_RADIUS, FOV_LIGHT_WALLS, FOV_ALGO);
+
     // Return UsedUp result
     UseResult::UsedUp
 }
@@ -1859,6 +6017,21 @@ fn new_game() -> (Game, Vec<Object>) {
         base_power: 2,
         xp: 0,
         on_death: DeathCallback::Player,
+        confused_turns: 0,
+        flee_below: 0,
+        keep_distance: 0,
+        sight_radius: 0,
+        alertness: Alertness::Alert,
+        stealth: PLAYER_STEALTH,
+        guard_post: None,
+        last_seen: None,
+        ranged: None,
+        pack: None,
+        speed: NORMAL_SPEED,
+        energy: 0,
+        hasted_turns: 0,
+        slowed_turns: 0,
+        faction: Faction::Player,
     });
 
     // Give player life!
@@ -1867,12 +6040,22 @@ fn new_game() -> (Game, Vec<Object>) {
     // Create a list of objects
     let mut objects = vec![player];
 
-    // Define game
+    // Define game, starting in the surface town
     let mut game = Game {
-        map: make_map(&mut objects, 1),
+        map: make_town(&mut objects),
         messages: Messages::new(),
         inventory: vec![],
-        dungeon_level: 1,
+        dungeon_level: 0,
+        levels: BTreeMap::new(),
+        gold: STARTING_GOLD,
+        seed: 0,
+        theme: Theme::Standard,
+        hazards: vec![],
+        digging: None,
+        map_revision: 0,
+        dijkstra: Default::default(),
+        auto_move: None,
+        shots: vec![],
     };
 
     // Initial equipment: a dagger
@@ -1884,14 +6067,35 @@ fn new_game() -> (Game, Vec<Object>) {
         max_hp_bonus: 0,
         defense_bonus: 0,
         power_bonus: 2,
+        attack_delay: 0,
     });
     game.inventory.push(dagger);
 
+    // ... and a torch to see by
+    let mut torch = create_item(Item::Torch, 0, 0);
+    if let Some(equipment) = torch.equipment.as_mut() {
+        equipment.equipped = true;
+    }
+    game.inventory.push(torch);
+
+    // ... and a loyal dog
+    let (player_x, player_y) = objects[PLAYER].pos();
+    if let Some((x, y)) = free_tile_near(player_x, player_y, &game.map, &objects) {
+        objects.push(create_ally("dog", x, y));
+    }
+
     // Add a warm welcoming message!
     game.messages.add(
         "Welcome to Qwestr! Prepare to perish in the Tombs of the Fallen Heroes...",
         GOLD,
     );
+    game.messages.add(
+        format!(
+            "Take the Crown of the Fallen Heroes from the tomb {} levels down, and bring it back to town.",
+            FINAL_DEPTH
+        ),
+        LIGHT_GREY,
+    );
 
     // Return game, objects
     (game, objects)
@@ -1899,15 +6103,16 @@ fn new_game() -> (Game, Vec<Object>) {
 
 /// Initialize FOV
 fn initialise_fov(tcod: &mut Tcod, map: &Map) {
-    // Create the FOV map, according to the generated map
-    for y in 0..MAP_HEIGHT {
-        for x in 0..MAP_WIDTH {
-            tcod.fov.set(
-                x,
-                y,
-                !map[x as usize][y as usize].block_sight,
-                !map[x as usize][y as usize].blocked,
-            );
+    // Create the FOV maps (for the player's sight, and for spreading light), matching the size of the generated map
+    let (map_width, map_height) = map_size(map);
+    tcod.fov = FovMap::new(map_width, map_height);
+    tcod.light_fov = FovMap::new(map_width, map_height);
+    for y in 0..map_height {
+        for x in 0..map_width {
+            let transparent = !map[x as usize][y as usize].block_sight;
+            let walkable = !map[x as usize][y as usize].blocked;
+            tcod.fov.set(x, y, transparent, walkable);
+            tcod.light_fov.set(x, y, transparent, walkable);
         }
     }
 }
@@ -1929,11 +6134,14 @@ fn play_game(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) {
         // Determine if FOV should be recomputed
         let fov_recompute = previous_player_position != objects[PLAYER].pos();
 
-        // Check for input event
+        // Check for input event; clicks and key presses only count in the frame they happen
+        tcod.key = Default::default();
+        tcod.mouse.lbutton_pressed = false;
+        tcod.mouse.rbutton_pressed = false;
         match input::check_for_event(input::MOUSE | input::KEY_PRESS) {
             Some((_, Event::Mouse(m))) => tcod.mouse = m,
             Some((_, Event::Key(k))) => tcod.key = k,
-            _ => tcod.key = Default::default(),
+            _ => {}
         }
 
         // Render the screen
@@ -1948,40 +6156,212 @@ fn play_game(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) {
         // Get player object
         let player = &mut objects[0];
 
-        // Save current position
+        // Save current position and health
         previous_player_position = (player.x, player.y);
+        let previous_player_hp = player.fighter.map_or(0, |f| f.hp);
+        let previous_player_energy = player.fighter.map_or(0, |f| f.energy);
+        let previous_player_xp = player.fighter.map_or(0, |f| f.xp);
+        let player_was_alive = player.alive;
+
+        // Get player action, carrying on with any automatic movement until a key is pressed or
+        // the mouse is clicked
+        let player_action = match game.auto_move {
+            Some(_) if tcod.key.code == KeyCode::NoKey && !tcod.mouse.lbutton_pressed => {
+                auto_move(tcod, game, objects)
+            }
+            Some(_) => {
+                game.auto_move = None;
+                PlayerAction::DidntTakeTurn
+            }
+            None => handle_keys(tcod, game, objects),
+        };
 
-        // Get player action
-        let player_action = handle_keys(tcod, game, objects);
-
-        // Save & Exit the game if Exit action was taken
+        // Save & Exit the game if Exit action was taken (there's nothing left to save once dead)
         if player_action == PlayerAction::Exit {
-            save_game(game, objects).unwrap();
+            if objects[PLAYER].alive {
+                save_game(game, objects).unwrap();
+            }
+            break;
+        }
+
+        // Escaping with the crown ends the run in victory
+        if player_action == PlayerAction::Won {
+            victory(tcod, game, objects);
             break;
         }
 
-        // Let monsters take their turn
+        if player_action == PlayerAction::TookTurn {
+            // Forget the projectiles shown since the last turn
+            game.shots.clear();
+
+            // Spring any trap the player stepped on, and make doors creak
+            if objects[PLAYER].pos() != previous_player_position {
+                let (x, y) = objects[PLAYER].pos();
+                if game.map[x as usize][y as usize].door {
+                    make_noise(Noise::Door, x, y, tcod, game, objects);
+                }
+                if let Some(trap_id) = trap_at(x, y, objects) {
+                    trigger_trap(trap_id, tcod, game, objects);
+                }
+            }
+
+            // Actions without a cost of their own take a normal turn
+            if objects[PLAYER].fighter.map_or(false, |f| f.energy == previous_player_energy) {
+                spend_energy(&mut objects[PLAYER], ACTION_COST);
+            }
+
+            // Burn down the player's light
+            burn_fuel(game);
+
+            // Let the player's confusion wear off
+            if let Some(fighter) = objects[PLAYER].fighter.as_mut() {
+                if fighter.confused_turns > 0 {
+                    fighter.confused_turns -= 1;
+                    if fighter.confused_turns == 0 {
+                        game.messages.add("You are no longer confused.", LIGHT_CYAN);
+                    }
+                }
+            }
+        }
+
+        // Let monsters act until the player is ready for their next action
         if objects[PLAYER].alive && player_action != PlayerAction::DidntTakeTurn {
-            for id in 0..objects.len() {
-                if objects[id].ai.is_some() {
-                    ai_take_turn(id, &tcod, game, objects);
+            run_game_turns(tcod, game, objects);
+        }
+
+        // Let the player know about the experience they gained, whoever made the kill
+        let xp_gained = objects[PLAYER].fighter.map_or(0, |f| f.xp) - previous_player_xp;
+        if xp_gained > 0 {
+            game.messages.add(format!("You gain {} experience points.", xp_gained), ORANGE);
+        }
+
+        // Stop any automatic movement as soon as the player gets hurt
+        if objects[PLAYER].fighter.map_or(0, |f| f.hp) < previous_player_hp {
+            game.auto_move = None;
+        }
+
+        // Record the run in the history as soon as the player dies, and throw away its save
+        if player_was_alive && !objects[PLAYER].alive {
+            if let Err(e) = record_run(RunRecord::new(RunOutcome::Died, game, objects)) {
+                game.messages.add(format!("Could not record the run: {}", e), RED);
+            }
+            if let Err(e) = delete_save() {
+                game.messages.add(format!("Could not delete the saved game: {}", e), RED);
+            }
+        }
+    }
+}
+
+/// Let game turns pass until the player has the energy to act again. Every turn everyone
+/// gains energy by their speed, and monsters act for as long as they have energy left, so
+/// fast monsters may act several times in a turn and slow ones only every other turn.
+fn run_game_turns(tcod: &Tcod, game: &mut Game, objects: &mut [Object]) {
+    while objects[PLAYER].alive && objects[PLAYER].fighter.map_or(false, |f| f.energy < 0) {
+        // Hand out energy to the player and the monsters
+        for (id, object) in objects.iter_mut().enumerate() {
+            if id != PLAYER && object.ai.is_none() {
+                continue;
+            }
+            let speed = object.speed();
+            if let Some(fighter) = object.fighter.as_mut() {
+                fighter.energy += speed;
+            }
+        }
+
+        // Let every monster with energy to spare act
+        for id in 0..objects.len() {
+            while objects[PLAYER].alive
+                && objects[id].ai.is_some()
+                && objects[id].fighter.map_or(false, |f| f.energy >= 0)
+            {
+                let energy = objects[id].fighter.map_or(0, |f| f.energy);
+                ai_take_turn(id, tcod, game, objects);
+
+                // Actions without a cost of their own take a normal turn
+                if objects[id].fighter.map_or(false, |f| f.energy == energy) {
+                    spend_energy(&mut objects[id], ACTION_COST);
+                }
+            }
+        }
+
+        // Let fire and gas do their work, and haste and slowness wear off
+        update_hazards(tcod, game, objects);
+        wear_off_speed(game, objects);
+    }
+}
+
+// Count down haste and slowness, letting the player know when theirs wears off
+fn wear_off_speed(game: &mut Game, objects: &mut [Object]) {
+    for (id, object) in objects.iter_mut().enumerate() {
+        if let Some(fighter) = object.fighter.as_mut() {
+            if fighter.hasted_turns > 0 {
+                fighter.hasted_turns -= 1;
+                if fighter.hasted_turns == 0 && id == PLAYER {
+                    game.messages.add("You feel yourself slow down.", LIGHT_CYAN);
+                }
+            }
+            if fighter.slowed_turns > 0 {
+                fighter.slowed_turns -= 1;
+                if fighter.slowed_turns == 0 && id == PLAYER {
+                    game.messages.add("You feel yourself speed up again.", LIGHT_CYAN);
                 }
             }
         }
     }
 }
 
+/// Show the victory screen, record the won run in the history and throw away its save
+fn victory(tcod: &mut Tcod, game: &Game, objects: &[Object]) {
+    let record = RunRecord::new(RunOutcome::Won, game, objects);
+    let mut text = format!(
+        "VICTORY!\n\nYou climb out of the dungeon into the daylight, carrying the Crown of the \
+         Fallen Heroes. The town will sing of you for generations.\n\n\
+         Character level: {}\nDeepest level reached: {}\nGold: {}\n",
+        record.character_level, record.deepest_level, record.gold
+    );
+    if let Err(e) = record_run(record) {
+        text.push_str(&format!("\nCould not record the run: {}\n", e));
+    }
+    if let Err(e) = delete_save() {
+        text.push_str(&format!("\nCould not delete the saved game: {}\n", e));
+    }
+    message_box(&text, HISTORY_SCREEN_WIDTH, &mut tcod.root);
+}
+
+// Burn the fuel of the player's equipped lights, throwing away those that burn out
+fn burn_fuel(game: &mut Game) {
+    for item in &mut game.inventory {
+        if !item.equipment.map_or(false, |e| e.equipped) {
+            continue;
+        }
+        if let Some(fuel) = item.light.as_mut().and_then(|light| light.fuel.as_mut()) {
+            *fuel -= 1;
+            if *fuel == TORCH_LOW_FUEL {
+                game.messages.add(format!("Your {} flickers.", item.name), ORANGE);
+            } else if *fuel <= 0 {
+                game.messages.add(format!("Your {} burns out!", item.name), ORANGE);
+            }
+        }
+    }
+    game.inventory
+        .retain(|item| item.light.map_or(true, |light| light.fuel.map_or(true, |fuel| fuel > 0)));
+}
+
 /// Advance to the next level
 fn next_level(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) {
-    // Show end level message
-    game.messages.add(
-        "You take a moment to rest, and recover your strength.",
-        VIOLET,
-    );
+    // Only rest before a level not visited yet, so the stairs can't be used for free healing
+    let level = game.dungeon_level + 1;
+    if !game.levels.contains_key(&level) {
+        // Show end level message
+        game.messages.add(
+            "You take a moment to rest, and recover your strength.",
+            VIOLET,
+        );
 
-    // Heal up to half of the player's max hp
-    let heal_hp = objects[PLAYER].max_hp(game) / 2;
-    objects[PLAYER].heal(heal_hp, game);
+        // Heal up to half of the player's max hp
+        let heal_hp = objects[PLAYER].max_hp(game) / 2;
+        objects[PLAYER].heal(heal_hp, game);
+    }
 
     // Show next level message
     game.messages.add(
@@ -1989,14 +6369,123 @@ fn next_level(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) {
         RED,
     );
 
-    // Increase dungeon level
-    game.dungeon_level += 1;
+    // Go one level deeper
+    change_level(tcod, game, objects, level);
 
-    // Make new map for level
-    game.map = make_map(objects, game.dungeon_level);
+    // Show the feeling of the level's theme
+    game.messages.add(game.theme.data().feeling, LIGHT_GREY);
+}
 
-    // Initialize FOV
+/// Return to the previous level
+fn previous_level(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) {
+    // Show previous level message
+    game.messages.add(
+        "You climb back up the stairs, towards the surface...",
+        VIOLET,
+    );
+
+    // Go one level up
+    let level = game.dungeon_level - 1;
+    change_level(tcod, game, objects, level);
+}
+
+/// Store the current level as it is, then enter (or generate) the given one
+fn change_level(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>, level: u32) {
+    // Player is the first element, everything else belongs to the level.
+    // NOTE: works only when the player is the first object!
+    assert_eq!(&objects[PLAYER] as *const _, &objects[0] as *const _);
+    let level_objects = objects.split_off(1);
+
+    // Allies close to the player follow them, even when confused
+    let (companions, level_objects): (Vec<_>, Vec<_>) = level_objects.into_iter().partition(|object| {
+        object.ai.is_some()
+            && object.fighter.map_or(false, |f| f.faction == Faction::Player)
+            && object.distance_to(&objects[PLAYER]) <= ALLY_FOLLOW_DISTANCE as f32
+    });
+
+    // Keep the level being left, so it can be restored later
+    let previous_level = game.dungeon_level;
+    let map = mem::take(&mut game.map);
+    game.levels.insert(
+        previous_level,
+        Level {
+            map,
+            objects: level_objects,
+            seed: game.seed,
+            theme: game.theme,
+            hazards: mem::take(&mut game.hazards),
+        },
+    );
+
+    // Arrive on the stairs leading back to the level being left
+    let arrival_stairs = if level > game.dungeon_level {
+        "up stairs"
+    } else {
+        "stairs"
+    };
+
+    // Update dungeon level, leaving any digging behind
+    game.dungeon_level = level;
+    game.digging = None;
+
+    match game.levels.remove(&level) {
+        Some(saved_level) => {
+            // Restore the level exactly as it was left
+            game.map = saved_level.map;
+            game.seed = saved_level.seed;
+            game.theme = saved_level.theme;
+            game.hazards = saved_level.hazards;
+            objects.extend(saved_level.objects);
+
+            // Place the player on the stairs they came through
+            let arrival = objects
+                .iter()
+                .find(|object| object.name == arrival_stairs)
+                .map(|object| object.pos());
+            if let Some((x, y)) = arrival {
+                objects[PLAYER].set_pos(x, y);
+            }
+        }
+        None => {
+            // Choose a random seed, and make new map for level
+            game.seed = rand::random();
+            let (map, theme) = generate_level(objects, level, game.seed);
+            game.map = map;
+            game.theme = theme;
+        }
+    }
+
+    // Gather the allies that came along around the player. Those with no room to stand wait
+    // on the level being left instead.
+    let (player_x, player_y) = objects[PLAYER].pos();
+    for mut companion in companions {
+        match free_tile_near(player_x, player_y, &game.map, objects) {
+            Some((x, y)) => {
+                companion.set_pos(x, y);
+                companion.path.clear();
+                objects.push(companion);
+            }
+            None => {
+                game.messages.add(format!("Your {} is left behind.", companion.name), LIGHT_RED);
+                if let Some(previous) = game.levels.get_mut(&previous_level) {
+                    previous.objects.push(companion);
+                }
+            }
+        }
+    }
+
+    // Warn the player when they reach the Fallen King's tomb, however they got there
+    if level == FINAL_DEPTH && level > previous_level {
+        game.messages.add(
+            "A deathly chill fills the air. You have entered the tomb of the Fallen King.",
+            LIGHT_VIOLET,
+        );
+    }
+
+    // Initialize FOV, and compute it right away in case the player didn't move
     initialise_fov(tcod, &game.map);
+    let player = &objects[PLAYER];
+    tcod.fov.compute_fov(player.x, player.y, FOV_RADIUS, FOV_LIGHT_WALLS, FOV_ALGO);
 }
 
 /// Promote a character to the next level
@@ -2098,7 +6587,7 @@ fn main_menu(tcod: &mut Tcod) {
         );
 
         // Show options and wait for the player's choice
-        let choices = &["Play New Game", "Continue Last Game", "Quit"];
+        let choices = &["Play New Game", "Continue Last Game", "Run History", "Quit"];
         let choice = menu("", choices, 24, &mut tcod.root);
 
         match choice {  
@@ -2124,6 +6613,20 @@ fn main_menu(tcod: &mut Tcod) {
                 }
             }
             Some(2) => {
+                // Show the most recent runs, newest first
+                let text = match load_history() {
+                    Ok(history) if !history.is_empty() => history
+                        .iter()
+                        .rev()
+                        .take(HISTORY_RUNS_SHOWN)
+                        .map(|run| run.summary())
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                    _ => "No finished runs yet.".to_string(),
+                };
+                message_box(&format!("Run History\n\n{}\n", text), HISTORY_SCREEN_WIDTH, &mut tcod.root);
+            }
+            Some(3) => {
                 // Quit
                 break;
             }
@@ -2165,7 +6668,200 @@ fn load_game() -> Result<(Game, Vec<Object>), Box<dyn Error>> {
     Ok(result)
 }
 
+// How a run ended
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum RunOutcome {
+    Won,
+    Died,
+}
+
+// A finished run, as kept in the run history
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct RunRecord {
+    outcome: RunOutcome,
+    character_level: i32,
+    dungeon_level: u32,
+    deepest_level: u32,
+    gold: i32,
+}
+
+impl RunRecord {
+    pub fn new(outcome: RunOutcome, game: &Game, objects: &[Object]) -> Self {
+        RunRecord {
+            outcome,
+            character_level: objects[PLAYER].level,
+            dungeon_level: game.dungeon_level,
+            deepest_level: game.levels.keys().cloned().fold(game.dungeon_level, cmp::max),
+            gold: game.gold,
+        }
+    }
+
+    // Describe the run in a line of the run history
+    pub fn summary(&self) -> String {
+        match self.outcome {
+            RunOutcome::Won => format!(
+                "Won with the crown as a level {} hero, with {} gold",
+                self.character_level, self.gold
+            ),
+            RunOutcome::Died => format!(
+                "Died on dungeon level {} as a level {} hero (deepest: {})",
+                self.dungeon_level, self.character_level, self.deepest_level
+            ),
+        }
+    }
+}
+
+/// Delete the saved game, if there is one
+fn delete_save() -> io::Result<()> {
+    match fs::remove_file("savegame") {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Add a finished run to the run history
+fn record_run(record: RunRecord) -> Result<(), Box<dyn Error>> {
+    // Start a new history if there is none yet, but never write over one that can't be read
+    let mut history = match load_history() {
+        Ok(history) => history,
+        Err(e) if e.downcast_ref::<io::Error>().map_or(false, |e| e.kind() == ErrorKind::NotFound) => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    history.push(record);
+
+    // Write the whole history back out
+    let mut file = File::create(HISTORY_FILE)?;
+    file.write_all(serde_json::to_string(&history)?.as_bytes())?;
+    Ok(())
+}
+
+/// Load the history of finished runs
+fn load_history() -> Result<Vec<RunRecord>, Box<dyn Error>> {
+    let mut json_history = String::new();
+    let mut file = File::open(HISTORY_FILE)?;
+    file.read_to_string(&mut json_history)?;
+    let history = serde_json::from_str::<Vec<RunRecord>>(&json_history)?;
+    Ok(history)
+}
+
+// A debug snapshot of a level: its ASCII rows and the positions of its objects
+#[derive(Serialize)]
+struct MapDump<'a> {
+    level: u32,
+    seed: u64,
+    theme: Theme,
+    width: i32,
+    height: i32,
+    rows: Vec<String>,
+    objects: Vec<ObjectDump<'a>>,
+}
+
+#[derive(Serialize)]
+struct ObjectDump<'a> {
+    name: &'a str,
+    char: char,
+    x: i32,
+    y: i32,
+}
+
+/// Draw a level as ASCII text: wall/ floor glyphs, with object characters on top
+fn map_to_ascii(map: &Map, objects: &[Object]) -> Vec<String> {
+    let (map_width, map_height) = map_size(map);
+
+    // Start with the tiles
+    let mut grid: Vec<Vec<char>> = (0..map_height)
+        .map(|y| {
+            (0..map_width)
+                .map(|x| {
+                    let tile = &map[x as usize][y as usize];
+                    match (tile.blocked, tile.door, tile.hidden, tile.flammable) {
+                        (_, true, true, _) => 'S',
+                        (_, true, false, _) => '+',
+                        (true, _, _, _) => '#',
+                        (false, _, _, true) => '"',
+                        (false, _, _, false) => '.',
+                    }
+                })
+                .collect()
+        })
+        .collect();
+
+    // Overlay the objects, blocking ones (monsters, the player) last
+    let mut to_draw: Vec<_> = objects.iter().collect();
+    to_draw.sort_by_key(|object| object.blocks);
+    for object in to_draw {
+        grid[object.y as usize][object.x as usize] = object.char;
+    }
+
+    // Return the rows of text
+    grid.into_iter().map(|row| row.into_iter().collect()).collect()
+}
+
+/// Dump a level to an ASCII text file and a JSON file, for debugging the generators
+fn dump_map(map: &Map, objects: &[Object], level: u32, seed: u64, theme: Theme) -> Result<(), Box<dyn Error>> {
+    let rows = map_to_ascii(map, objects);
+    let (width, height) = map_size(map);
+
+    // Write the ASCII dump
+    let mut file = File::create(MAP_DUMP_TEXT_FILE)?;
+    file.write_all(rows.join("\n").as_bytes())?;
+
+    // Serialize the structured dump to json, and write it
+    let dump = MapDump {
+        level,
+        seed,
+        theme,
+        width,
+        height,
+        rows,
+        objects: objects
+            .iter()
+            .map(|object| ObjectDump {
+                name: &object.name,
+                char: object.char,
+                x: object.x,
+                y: object.y,
+            })
+            .collect(),
+    };
+    let file = File::create(MAP_DUMP_JSON_FILE)?;
+    serde_json::to_writer(file, &dump)?;
+
+    // Return successful result
+    Ok(())
+}
+
+/// Generate a level from a seed without playing, and dump it to files
+fn dump_generated_map(args: &[String]) -> Result<(), Box<dyn Error>> {
+    // Parse the seed and level arguments
+    let seed = args.first().ok_or("missing seed")?.parse::<u64>()?;
+    let level = args.get(1).ok_or("missing level")?.parse::<u32>()?;
+
+    // Generate the level around a stand-in player
+    let mut player = Object::new(0, 0, '@', "player", WHITE, true);
+    player.alive = true;
+    let mut objects = vec![player];
+    let (map, theme) = generate_level(&mut objects, level, seed);
+
+    // Dump it
+    dump_map(&map, &objects, level, seed, theme)
+}
+
 fn main() {
+    // Dump a generated map without playing: `qwest_r --dump-map <seed> <level>`
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(|arg| arg.as_str()) == Some("--dump-map") {
+        match dump_generated_map(&args[2..]) {
+            Ok(()) => println!("Map dumped to {} and {}.", MAP_DUMP_TEXT_FILE, MAP_DUMP_JSON_FILE),
+            Err(e) => {
+                eprintln!("Could not dump the map: {}", e);
+                eprintln!("Usage: qwest_r --dump-map <seed> <level>");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     // Define tcod implementation
     let root = Root::initializer()
         .font("arial10x10.png", FontLayout::Tcod)
@@ -2175,11 +6871,14 @@ fn main() {
         .init();
     let mut tcod = Tcod {
         root,
-        con: Offscreen::new(MAP_WIDTH, MAP_HEIGHT),
+        con: Offscreen::new(CAMERA_WIDTH, CAMERA_HEIGHT),
         panel: Offscreen::new(SCREEN_WIDTH, PANEL_HEIGHT),  
         fov: FovMap::new(MAP_WIDTH, MAP_HEIGHT),
+        light_fov: FovMap::new(MAP_WIDTH, MAP_HEIGHT),
+        lights: Default::default(),
         key: Default::default(),
         mouse: Default::default(),
+        camera: (0, 0),
     };
     
     // Define FPS
@@ -2187,4 +6886,196 @@ fn main() {
 
     // Show the main menu
     main_menu(&mut tcod);
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A level that hasn't been dug into yet
+    fn solid_map() -> Map {
+        vec![vec![Tile::wall(); MAP_HEIGHT as usize]; MAP_WIDTH as usize]
+    }
+
+    #[test]
+    fn prefab_fits_only_on_untouched_walls() {
+        let prefab = &PREFABS[0];
+        let mut map = solid_map();
+        assert!(prefab_fits(prefab, 10, 10, &map));
+
+        // Open floor under the prefab, or in the margin around it, is in the way
+        map[12][12] = Tile::empty();
+        assert!(!prefab_fits(prefab, 10, 10, &map));
+        let mut map = solid_map();
+        map[9][10] = Tile::empty();
+        assert!(!prefab_fits(prefab, 10, 10, &map));
+    }
+
+    #[test]
+    fn doors_never_tunnel_through_vaults() {
+        let mut map = solid_map();
+        let room = Rect::new(30, 10, 6, 6);
+        let vault = Rect::new(20, 10, 6, 6);
+        let other_vault = Rect::new(10, 10, 6, 6);
+
+        // A door facing the room is connected to it, around the vault in between
+        let blocking_vault = Rect::new(28, 8, 1, 12);
+        connect_door((26, 13, 1, 0), &[room], &[vault, blocking_vault], &mut map);
+        assert!(!map[33][13].blocked);
+        for x in 0..MAP_WIDTH {
+            for y in 0..MAP_HEIGHT {
+                if blocking_vault.contains(x, y) {
+                    assert!(map[x as usize][y as usize].blocked);
+                }
+            }
+        }
+
+        // A door facing away from every room is walled up
+        let mut map = solid_map();
+        map[16][13] = Tile::door();
+        connect_door((16, 13, -1, 0), &[room], &[vault, other_vault], &mut map);
+        assert!(map[16][13].blocked);
+        assert!(map[15][13].blocked);
+    }
+
+    #[test]
+    fn reachability_follows_passable_tiles() {
+        let mut map = solid_map();
+        create_h_tunnel(5, 15, 5, &mut map);
+        assert!(is_reachable((5, 5), (15, 5), &map));
+        map[10][5] = Tile::secret_door();
+        assert!(!is_reachable((5, 5), (15, 5), &map));
+        map[10][5] = Tile::door();
+        assert!(is_reachable((5, 5), (15, 5), &map));
+    }
+
+    #[test]
+    fn secret_doors_keep_the_stairs_reachable() {
+        // Two rooms joined by a single tunnel, so hiding its doorway would cut off the stairs
+        let mut map = solid_map();
+        let rooms = [Rect::new(2, 2, 6, 6), Rect::new(20, 2, 6, 6)];
+        for room in &rooms {
+            create_room(*room, &mut map);
+        }
+        create_h_tunnel(4, 23, 5, &mut map);
+        let (start, stairs) = (rooms[0].center(), rooms[1].center());
+
+        for seed in 0..20 {
+            let mut level_map = map.clone();
+            place_doors(&rooms, start, stairs, &mut level_map, 10, &mut StdRng::seed_from_u64(seed));
+            assert!(is_reachable(start, stairs, &level_map));
+            assert!(level_map[8][5].door && !level_map[8][5].hidden);
+            assert!(level_map[20][5].door && !level_map[20][5].hidden);
+        }
+    }
+
+    #[test]
+    fn levels_are_generated_from_their_seed() {
+        let generate = |seed| {
+            let mut objects = vec![Object::new(0, 0, '@', "player", WHITE, true)];
+            let (map, theme) = generate_level(&mut objects, 3, seed);
+            (map_to_ascii(&map, &objects), theme)
+        };
+        assert_eq!(generate(42), generate(42));
+        assert_ne!(generate(42).0, generate(43).0);
+    }
+
+    #[test]
+    fn paths_go_around_walls_and_traps() {
+        // A room split by a wall, with a single gap at its bottom end
+        let mut map = solid_map();
+        create_room(Rect::new(0, 0, 20, 10), &mut map);
+        for tile in &mut map[10][1..9] {
+            *tile = Tile::wall();
+        }
+        let path_avoiding = |objects: &[Object], map: &Map| {
+            find_path((5, 5), (15, 5), map, |x, y| {
+                if map[x as usize][y as usize].blocked || trap_at(x, y, objects).is_some() {
+                    None
+                } else {
+                    Some(1)
+                }
+            })
+        };
+
+        let path = path_avoiding(&[], &map).expect("there is a way through the gap");
+        assert_eq!(path.last(), Some(&(15, 5)));
+        assert!(path.contains(&(10, 9)));
+        assert!(path.iter().all(|&(x, y)| !map[x as usize][y as usize].blocked));
+
+        // A trap in the gap, or closing it, leaves no way through
+        let traps = [create_trap(TrapKind::Dart, 10, 9)];
+        assert_eq!(path_avoiding(&traps, &map), None);
+        map[10][9] = Tile::wall();
+        assert_eq!(path_avoiding(&[], &map), None);
+    }
+
+    #[test]
+    fn dijkstra_maps_count_steps_and_lead_away() {
+        let mut map = solid_map();
+        create_room(Rect::new(0, 0, 20, 10), &mut map);
+        let passable = |x: i32, y: i32| !map[x as usize][y as usize].blocked;
+        let approach = DijkstraMap::new(&map, &[(5, 5)], passable);
+        assert_eq!(approach.distance(5, 5), 0);
+        assert_eq!(approach.distance(8, 6), 3);
+        assert_eq!(approach.distance(15, 1), 10);
+        assert_eq!(approach.distance(0, 0), i32::MAX);
+        assert_eq!(approach.distance(-1, 5), i32::MAX);
+
+        // Rolling down the flee map takes a monster further from the source
+        let flee = approach.flee(&map, passable);
+        let (x, y) = (7, 5);
+        let next = (-1..2)
+            .flat_map(|dx| (-1..2).map(move |dy| (x + dx, y + dy)))
+            .filter(|&(x, y)| passable(x, y))
+            .min_by_key(|&(x, y)| flee.distance(x, y))
+            .unwrap();
+        assert!(approach.distance(next.0, next.1) > approach.distance(x, y));
+    }
+
+    #[test]
+    fn factions_are_hostile_both_ways() {
+        let factions = [Faction::Player, Faction::Orcs, Faction::Undead, Faction::Wildlife];
+        for &a in &factions {
+            assert!(!a.is_hostile_to(a));
+            for &b in &factions {
+                assert_eq!(a.is_hostile_to(b), b.is_hostile_to(a));
+            }
+        }
+        assert!(Faction::Orcs.is_hostile_to(Faction::Undead));
+        assert!(Faction::Wildlife.is_hostile_to(Faction::Player));
+        assert!(!Faction::Orcs.is_hostile_to(Faction::Wildlife));
+    }
+
+    #[test]
+    fn no_trapdoors_at_the_final_depth() {
+        assert!(from_dungeon_level(TRAPDOOR_CHANCE, FINAL_DEPTH - 1) > 0);
+        assert_eq!(from_dungeon_level(TRAPDOOR_CHANCE, FINAL_DEPTH), 0);
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..500 {
+            assert_ne!(random_trap_kind(FINAL_DEPTH, &mut rng), TrapKind::Trapdoor);
+        }
+    }
+
+    #[test]
+    fn elites_get_their_stat_bonuses() {
+        let base = create_monster("orc", 0, 0).fighter.unwrap();
+        let hulking = ELITES.iter().find(|elite| elite.prefix == "Hulking").unwrap();
+        let swift = ELITES.iter().find(|elite| elite.prefix == "Swift").unwrap();
+
+        let mut elite = create_monster("orc", 0, 0);
+        make_elite(&mut elite, hulking);
+        let fighter = elite.fighter.unwrap();
+        assert_eq!(elite.name, "Hulking orc");
+        assert_eq!(fighter.base_max_hp, base.base_max_hp * 2);
+        assert_eq!(fighter.hp, fighter.base_max_hp);
+        assert_eq!(fighter.base_power, base.base_power + 1);
+        assert_eq!(fighter.base_defense, base.base_defense + 1);
+        assert_eq!(fighter.xp, base.xp + base.xp * 60 / 100);
+        assert_eq!(fighter.speed, base.speed);
+
+        let mut elite = create_monster("orc", 0, 0);
+        make_elite(&mut elite, swift);
+        assert_eq!(elite.fighter.unwrap().speed, base.speed * 3 / 2);
+    }
+}